@@ -1,6 +1,9 @@
 use std::env;
 
+/// The socket path given while building is used when `DIMPORT_SOCKET` is not set at runtime
 fn main() {
-    let socket_path = env::var("SOCKET_PATH").unwrap_or("/tmp/dimportd.socket".into());
-    println!("cargo:rustc-env=SOCKET_PATH={}", socket_path);
+    println!("cargo:rerun-if-env-changed=SOCKET_PATH");
+    if let Ok(socket_path) = env::var("SOCKET_PATH") {
+        println!("cargo:rustc-env=SOCKET_PATH={}", socket_path);
+    }
 }
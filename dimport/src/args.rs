@@ -7,20 +7,41 @@ pub enum Ignore {
     Search(Regex),
 }
 pub enum Set {
-    Repository(String),
+    /// Url, push, bundle path and force
+    Repository((String, bool, Option<PathBuf>, bool)),
     Home(PathBuf),
     PrivateKey(PathBuf),
+    EncryptionKey(PathBuf),
+    Links(String),
+    Profiles(Vec<String>),
+    Variable((String, String)),
+}
+
+pub enum ConfigCommand {
+    Show,
+    List,
+    Get(String),
+    Set((String, String)),
+    Unset(String),
 }
 
 pub enum Args {
     Init((PathBuf, Option<String>)),
     Status,
-    Config,
+    Config(ConfigCommand),
     Sync,
     Set(Set),
     Ignore(Ignore),
     Restore(Regex),
-    Add(PathBuf),
+    Add((Vec<PathBuf>, bool, bool)),
+    Forget(String),
+    Move((PathBuf, PathBuf)),
+    Ls(bool),
+    Which(PathBuf),
+    Doctor(bool),
+    LinkDir(PathBuf),
+    Map((PathBuf, String)),
+    Unmap(PathBuf),
     Pick(Regex),
     Unpick(Regex),
     Save(Option<String>),
@@ -50,13 +71,60 @@ impl Args {
                     return Ok(Args::Init((home_path, repository)));
                 }
                 "status" => return Ok(Args::Status),
-                "config" => return Ok(Args::Config),
+                "config" => match args.next().map(|a| a.as_str()) {
+                    None => return Ok(Args::Config(ConfigCommand::Show)),
+                    Some("list") => return Ok(Args::Config(ConfigCommand::List)),
+                    Some("get") => {
+                        if let Some(key) = args.next() {
+                            return Ok(Args::Config(ConfigCommand::Get(key.clone())));
+                        }
+                    }
+                    Some("set") => {
+                        if let Some(key) = args.next() {
+                            let value = args
+                                .map(|a| a.to_owned())
+                                .collect::<Vec<String>>()
+                                .join(" ");
+                            if !value.is_empty() {
+                                return Ok(Args::Config(ConfigCommand::Set((key.clone(), value))));
+                            }
+                        }
+                    }
+                    Some("unset") => {
+                        if let Some(key) = args.next() {
+                            return Ok(Args::Config(ConfigCommand::Unset(key.clone())));
+                        }
+                    }
+                    _ => {}
+                },
                 "sync" => return Ok(Args::Sync),
                 "set" => {
                     if let Some(arg) = args.next() {
                         if arg.eq("repo") {
                             if let Some(repo) = args.next() {
-                                return Ok(Args::Set(Set::Repository(repo.clone())));
+                                let mut push = false;
+                                let mut bundle = None;
+                                let mut force = false;
+                                while let Some(arg) = args.next() {
+                                    match arg.as_str() {
+                                        "--push" => push = true,
+                                        "--bundle" => match args.next() {
+                                            // the daemon runs somewhere else
+                                            Some(path) => {
+                                                bundle = Some(env::current_dir()?.join(path))
+                                            }
+                                            None => return Err("--bundle needs a path".into()),
+                                        },
+                                        "--force" => force = true,
+                                        _ => return Err(format!("Unknown option {}", arg).into()),
+                                    }
+                                }
+                                return Ok(Args::Set(Set::Repository((
+                                    repo.clone(),
+                                    push,
+                                    bundle,
+                                    force,
+                                ))));
                             }
                         } else if arg.eq("home") {
                             if let Some(home) = args.next() {
@@ -80,6 +148,36 @@ impl Args {
                                 }
                                 return Ok(Args::Set(Set::PrivateKey(path)));
                             }
+                        } else if arg.eq("encryption_key") {
+                            if let Some(path) = args.next() {
+                                let path = PathBuf::from(path);
+                                if !path.is_absolute() {
+                                    return Err("Please give the absolute path".into());
+                                }
+                                return Ok(Args::Set(Set::EncryptionKey(path)));
+                            }
+                        } else if arg.eq("profiles") {
+                            let profiles = args
+                                .flat_map(|a| a.split(','))
+                                .filter(|p| !p.is_empty())
+                                .map(|p| p.to_owned())
+                                .collect();
+                            return Ok(Args::Set(Set::Profiles(profiles)));
+                        } else if arg.eq("variable") {
+                            if let Some(name) = args.next() {
+                                let value = args
+                                    .map(|a| a.to_owned())
+                                    .collect::<Vec<String>>()
+                                    .join(" ");
+                                return Ok(Args::Set(Set::Variable((name.clone(), value))));
+                            }
+                        } else if arg.eq("links") {
+                            if let Some(style) = args.next() {
+                                if style != "absolute" && style != "relative" {
+                                    return Err("Link style has to be absolute or relative".into());
+                                }
+                                return Ok(Args::Set(Set::Links(style.clone())));
+                            }
                         }
                     }
                 }
@@ -100,12 +198,86 @@ impl Args {
                     }
                 }
                 "add" => {
+                    let mut encrypt = false;
+                    let mut confirmed = false;
+                    let mut paths = vec![];
+                    for arg in args {
+                        match arg.as_str() {
+                            "--encrypt" => encrypt = true,
+                            "--yes" => confirmed = true,
+                            _ => {
+                                let path = PathBuf::from(arg);
+                                // glob patterns are expanded by the daemon
+                                let is_pattern = arg.contains(&['*', '?', '['][..]);
+                                if !is_pattern && !path.exists() {
+                                    return Err(format!("Path {} does not exist", arg).into());
+                                }
+                                paths.push(path);
+                            }
+                        }
+                    }
+                    if !paths.is_empty() {
+                        return Ok(Args::Add((paths, encrypt, confirmed)));
+                    }
+                }
+                "map" => {
+                    if let (Some(source), Some(target)) = (args.next(), args.next()) {
+                        let source = PathBuf::from(source);
+                        if source.is_absolute() {
+                            return Err("Source has to be relative to the repository".into());
+                        }
+                        return Ok(Args::Map((source, target.to_owned())));
+                    }
+                }
+                "unmap" => {
+                    if let Some(source) = args.next() {
+                        return Ok(Args::Unmap(PathBuf::from(source)));
+                    }
+                }
+                "forget" => {
                     if let Some(arg) = args.next() {
                         let path = PathBuf::from(arg);
-                        if !path.exists() {
+                        // an existing path, otherwise a regex of repository paths
+                        if path.symlink_metadata().is_ok() {
+                            let path = env::current_dir()?.join(path);
+                            return Ok(Args::Forget(path.to_string_lossy().into_owned()));
+                        }
+                        Regex::new(arg)?;
+                        return Ok(Args::Forget(arg.to_owned()));
+                    }
+                }
+                "mv" => {
+                    if let (Some(old), Some(new)) = (args.next(), args.next()) {
+                        let old = env::current_dir()?.join(old);
+                        let new = env::current_dir()?.join(new);
+                        if old.symlink_metadata().is_err() {
                             return Err("Path does not exist".into());
                         }
-                        return Ok(Args::Add(path));
+                        return Ok(Args::Move((old, new)));
+                    }
+                }
+                "ls" => match args.next().map(|a| a.as_str()) {
+                    Some("--tree") => return Ok(Args::Ls(true)),
+                    None => return Ok(Args::Ls(false)),
+                    _ => {}
+                },
+                "which" => {
+                    if let Some(arg) = args.next() {
+                        return Ok(Args::Which(env::current_dir()?.join(arg)));
+                    }
+                }
+                "doctor" => match args.next().map(|a| a.as_str()) {
+                    Some("--fix") => return Ok(Args::Doctor(true)),
+                    None => return Ok(Args::Doctor(false)),
+                    _ => {}
+                },
+                "link_dir" => {
+                    if let Some(arg) = args.next() {
+                        let path = PathBuf::from(arg);
+                        if !path.is_dir() {
+                            return Err("Path is not a directory".into());
+                        }
+                        return Ok(Args::LinkDir(path));
                     }
                 }
                 "pick" => {
@@ -142,16 +314,41 @@ Usage:
 Unitialized state commands:
     init [<url>]                                Load config.json with sane defaults and optionally give the repository aswell (will only work when no config setup)
     set [repo|home|private_key] [<url>|<path>]  Configure the dotfiles importer
+    set links [absolute|relative]               Create absolute or relative symlinks
+    set encryption_key <path>                   Key for encrypted files, a new key is generated if path does not exist
+    set profiles [<profile>...]                 Activate profiles, files tagged with other profiles are not deployed
     config                                      Return current configuration
+    config list                                 List every config key with its value and description
+    config get <key>                            Return the value of a config key
+    config set <key> <value>...                 Validate and set a config key, lists are separated by commas
+    config unset <key>                          Reset a config key to its default
 
 Commands:
     status                                      Show changed files and show suggested files.
     config                                      Return current configuration
+    config list                                 List every config key with its value and description
+    config get <key>                            Return the value of a config key
+    config set <key> <value>...                 Validate and set a config key, lists are separated by commas
+    config unset <key>                          Reset a config key to its default
     sync                                        Synchronize files right now (otherwise being run every ~5 min)
     set [repo|home|private_key] [<url>|<path>]  Configure the dotfiles importer
+    set repo <url> [--push|--force]             Switch repositories, refuses with unsaved work unless it is pushed or forced
+    set repo <url> --bundle <path>              Export unpushed commits to a git bundle and switch repositories
+    set links [absolute|relative]               Create absolute or relative symlinks and rewrite existing links
+    set encryption_key <path>                   Key for encrypted files, a new key is generated if path does not exist
+    set profiles [<profile>...]                 Switch active profiles and relink files tagged with profiles
+    set variable <name> [<value>]               Set a template variable and render templates again
     ignore [all|<regex>]                        Ignore from suggested files
-    restore <regex>                             Restore a removed or changed file
-    add <path>                                  Add a file or directory to the repository
+    restore <regex>                             Restore a removed or changed file or render a drifted template again
+    add [--encrypt] [--yes] <path|glob>...      Add files, directories recursively, shows a preview unless --yes is given
+    map <source> <target>                       Deploy repository directory source to target, target can use ~ and $VARIABLES
+    unmap <source>                              Deploy source to the same path in home again
+    link_dir <path>                             Link a directory as a whole, files created in it are added to the repository
+    forget <path|regex>                         Stop tracking files but keep them in home, removed from the repository on save
+    mv <old> <new>                              Move a tracked file or directory in home and the repository, saved as a rename
+    ls [--tree]                                 List tracked files and whether their home target is linked correctly
+    which <path>                                Show the repository file deployed to a home path and its state
+    doctor [--fix]                              Check links, keys, repository and state, --fix repairs what it can
     pick <regex>                                Pick a files from Changed files to save instead of all
     unpick <regex>                              Unpick file from Picked Files if picked files is empty will save all
     save [<message>]                            Save current settings and give an optional description of changed files
@@ -1,11 +1,11 @@
 use std::io::prelude::*;
 use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 use std::{env, process};
 
 mod args;
-use args::{Args, Ignore};
+use args::{Args, ConfigCommand, Ignore};
 
-static SOCKET_PATH: &str = env!("SOCKET_PATH");
 static BUFFER_SIZE: usize = 10000;
 
 fn main() {
@@ -14,7 +14,7 @@ fn main() {
         process::exit(0)
     });
 
-    let mut stream = UnixStream::connect(SOCKET_PATH).unwrap_or_else(|e| {
+    let mut stream = UnixStream::connect(socket_path()).unwrap_or_else(|e| {
         eprintln!(
             "Could not connect to the daemon: {} \n\nmake sure dimportd is running.",
             e
@@ -42,14 +42,40 @@ fn main() {
         Args::Status => {
             write("status");
         }
-        Args::Config => write("config"),
+        Args::Config(command) => match command {
+            ConfigCommand::Show => write("config"),
+            ConfigCommand::List => write("config list"),
+            ConfigCommand::Get(key) => write(&format!("config get {}", key)),
+            ConfigCommand::Set((key, value)) => write(&format!("config set {} {}", key, value)),
+            ConfigCommand::Unset(key) => write(&format!("config unset {}", key)),
+        },
         Args::Sync => write("sync"),
         Args::Set(set) => match set {
-            args::Set::Repository(repo) => write(&format!("set repo {}", repo)),
+            args::Set::Repository((repo, push, bundle, force)) => {
+                let mut request = format!("set repo {}", repo);
+                if push {
+                    request.push_str(" --push");
+                }
+                if let Some(path) = bundle {
+                    request.push_str(&format!(" --bundle {}", path.to_str().unwrap()));
+                }
+                if force {
+                    request.push_str(" --force");
+                }
+                write(&request)
+            }
             args::Set::Home(path) => write(&format!("set home {}", path.to_str().unwrap())),
             args::Set::PrivateKey(path) => {
                 write(&format!("set private_key {}", path.to_str().unwrap()))
             }
+            args::Set::EncryptionKey(path) => {
+                write(&format!("set encryption_key {}", path.to_str().unwrap()))
+            }
+            args::Set::Links(style) => write(&format!("set links {}", style)),
+            args::Set::Variable((name, value)) => {
+                write(&format!("set variable {} {}", name, value))
+            }
+            args::Set::Profiles(profiles) => write(&format!("set profiles {}", profiles.join(","))),
         },
         Args::Ignore(ignore) => match ignore {
             Ignore::All => write("ignore all"),
@@ -60,9 +86,40 @@ fn main() {
         Args::Restore(regex) => {
             write(&format!("restore {}", regex));
         }
-        Args::Add(path) => {
+        Args::Add((paths, encrypt, confirmed)) => {
+            let mut request = String::from("add");
+            if encrypt {
+                request.push_str(" --encrypt");
+            }
+            if confirmed {
+                request.push_str(" --yes");
+            }
+            for path in paths {
+                // patterns do not exist yet so only make them absolute
+                let path = path
+                    .canonicalize()
+                    .unwrap_or_else(|_| env::current_dir().unwrap().join(path));
+                request.push(' ');
+                request.push_str(path.to_str().unwrap());
+            }
+            write(&request);
+        }
+        Args::Map((source, target)) => {
+            write(&format!("map {} {}", source.to_str().unwrap(), target));
+        }
+        Args::Unmap(source) => write(&format!("unmap {}", source.to_str().unwrap())),
+        Args::Forget(pattern) => write(&format!("forget {}", pattern)),
+        Args::Move((old, new)) => write(&format!(
+            "mv {} {}",
+            old.to_str().unwrap(),
+            new.to_str().unwrap()
+        )),
+        Args::Ls(tree) => write(if tree { "ls --tree" } else { "ls" }),
+        Args::Which(path) => write(&format!("which {}", path.to_str().unwrap())),
+        Args::Doctor(fix) => write(if fix { "doctor --fix" } else { "doctor" }),
+        Args::LinkDir(path) => {
             write(&format!(
-                "add {}",
+                "link_dir {}",
                 path.canonicalize().unwrap().to_str().unwrap()
             ));
         }
@@ -87,6 +144,23 @@ fn main() {
     }
 }
 
+/// Same as the daemon: `DIMPORT_SOCKET`, the path given while building or the runtime directory
+fn socket_path() -> PathBuf {
+    if let Some(path) = env::var_os("DIMPORT_SOCKET").filter(|path| !path.is_empty()) {
+        return PathBuf::from(path);
+    }
+    if let Some(path) = option_env!("SOCKET_PATH") {
+        return PathBuf::from(path);
+    }
+    match env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+        Some(path) if path.is_absolute() => path.join("dimport").join("dimportd.socket"),
+        _ => match env::var("USER") {
+            Ok(user) => PathBuf::from(format!("/tmp/dimportd-{}.socket", user)),
+            Err(_) => PathBuf::from("/tmp/dimportd.socket"),
+        },
+    }
+}
+
 fn raw(request: &str) -> Vec<u8> {
     let mut request = request.to_string().into_bytes();
     request.resize(BUFFER_SIZE, 0);
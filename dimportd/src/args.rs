@@ -0,0 +1,93 @@
+use dimportd::paths::Paths;
+use dimportd::DEFAULT_INTERVAL;
+use log::LevelFilter;
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub struct Args {
+    pub paths: Paths,
+    /// Run a single sync and exit
+    pub once: bool,
+    /// Validate the config and exit
+    pub check_config: bool,
+    /// Ask a running instance to hand over instead of exiting
+    pub replace: bool,
+    pub log_level: Option<LevelFilter>,
+    /// Time between synchronizations
+    pub interval: Duration,
+}
+
+impl Args {
+    pub fn from(args: Vec<String>) -> Result<Args, Box<dyn Error>> {
+        let mut parsed = Args {
+            paths: Paths::from_env(),
+            once: false,
+            check_config: false,
+            replace: false,
+            log_level: None,
+            interval: DEFAULT_INTERVAL,
+        };
+
+        let mut args = args.iter();
+        // Skip first argument
+        args.next();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--once" => parsed.once = true,
+                "--check-config" => parsed.check_config = true,
+                "--replace" => parsed.replace = true,
+                "--config" => parsed.paths.config = path(arg, args.next())?,
+                "--state" => parsed.paths.state = path(arg, args.next())?,
+                "--socket" => parsed.paths.socket = path(arg, args.next())?,
+                "--repository-dir" => parsed.paths.repository = path(arg, args.next())?,
+                "--backup-dir" => parsed.paths.backup = path(arg, args.next())?,
+                "--log-level" => match args.next() {
+                    Some(level) => {
+                        parsed.log_level = Some(level.parse().map_err(|_| {
+                            format!(
+                            "Invalid log level {:?}, use off, error, warn, info, debug or trace",
+                            level
+                        )
+                        })?)
+                    }
+                    None => return Err("--log-level needs a level".into()),
+                },
+                "--interval" => match args.next().map(|seconds| seconds.parse::<u64>()) {
+                    Some(Ok(seconds)) if seconds > 0 => {
+                        parsed.interval = Duration::from_secs(seconds)
+                    }
+                    _ => return Err("--interval needs a number of seconds above 0".into()),
+                },
+                _ => return Err(format!("Unknown argument {:?}\n\n{}", arg, USAGE).into()),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+fn path(flag: &str, value: Option<&String>) -> Result<PathBuf, Box<dyn Error>> {
+    match value {
+        Some(value) => Ok(PathBuf::from(value)),
+        None => Err(format!("{} needs a path", flag).into()),
+    }
+}
+
+pub const USAGE: &str = r#"Dotfiles Import Daemon
+Lyr-7D1h <lyr-7d1h@pm.me>
+Usage:
+    dimportd [<options>]
+
+Options:
+    --once                                      Synchronize once and exit instead of running in the foreground
+    --check-config                              Validate the config file and exit
+    --replace                                   Ask a running instance to stop and take over
+    --interval <seconds>                        Time between synchronizations, 300 by default
+    --log-level <level>                         off, error, warn, info, debug or trace, RUST_LOG is used otherwise
+    --config <path>                             Config file, overrides DIMPORT_CONFIG
+    --state <path>                              State file, overrides DIMPORT_STATE
+    --socket <path>                             Socket for the cli, overrides DIMPORT_SOCKET
+    --repository-dir <path>                     Clone of the repository, overrides DIMPORT_REPOSITORY_DIR
+    --backup-dir <path>                         Backups of replaced files, overrides DIMPORT_BACKUP_DIR
+"#;
@@ -0,0 +1,122 @@
+/// Marker starting a block, followed by conditions like `host=laptop profile=work,home`
+pub const IF_MARKER: &str = "dimport:if";
+pub const ELSE_MARKER: &str = "dimport:else";
+pub const ENDIF_MARKER: &str = "dimport:endif";
+
+pub fn has_blocks(content: &str) -> bool {
+    content.contains(IF_MARKER)
+}
+
+/// Comment out lines inside of blocks whose conditions do not apply
+pub fn deploy<F>(source: &str, applies: F) -> String
+where
+    F: Fn(&str) -> bool,
+{
+    transform(source, applies, |line, prefix| {
+        if line.is_empty() {
+            prefix.to_string()
+        } else {
+            format!("{} {}", prefix, line)
+        }
+    })
+}
+
+/// Reverse of deploy, uncomments lines inside of blocks whose conditions do not apply
+pub fn undeploy<F>(deployed: &str, applies: F) -> String
+where
+    F: Fn(&str) -> bool,
+{
+    transform(deployed, applies, |line, prefix| {
+        if line == prefix {
+            String::new()
+        } else {
+            line.strip_prefix(&format!("{} ", prefix))
+                .unwrap_or(line)
+                .to_string()
+        }
+    })
+}
+
+/// Apply op to every line in an inactive block with the comment prefix of the innermost block
+/// Marker lines are never changed
+fn transform<F, O>(content: &str, applies: F, op: O) -> String
+where
+    F: Fn(&str) -> bool,
+    O: Fn(&str, &str) -> String,
+{
+    // (active, comment prefix) of every open block
+    let mut blocks: Vec<(bool, String)> = vec![];
+    let mut result = String::with_capacity(content.len());
+
+    for line in content.split_inclusive('\n') {
+        let (text, ending) = match line.strip_suffix('\n') {
+            Some(text) => (text, "\n"),
+            None => (line, ""),
+        };
+
+        if let Some(index) = text.find(IF_MARKER) {
+            let prefix = text[..index].trim().to_string();
+            let conditions = text[index + IF_MARKER.len()..].trim();
+            blocks.push((applies(conditions), prefix));
+        } else if text.contains(ELSE_MARKER) {
+            if let Some(block) = blocks.last_mut() {
+                block.0 = !block.0;
+            }
+        } else if text.contains(ENDIF_MARKER) {
+            blocks.pop();
+        } else if blocks.iter().any(|(active, _)| !active) {
+            let (_, prefix) = blocks.last().unwrap();
+            result.push_str(&op(text, prefix));
+            result.push_str(ending);
+            continue;
+        }
+
+        result.push_str(line);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "# a comment
+export EDITOR=vim
+# dimport:if host=laptop
+export LAPTOP=1
+# # nested comment
+
+# dimport:else
+export DESKTOP=1
+# dimport:endif
+// dimport:if profile=work
+// dimport:if host=desktop
+inner
+// dimport:endif
+outer
+// dimport:endif
+";
+
+    fn applies(conditions: &str) -> bool {
+        conditions == "host=desktop"
+    }
+
+    #[test]
+    fn deploy_comments_inactive_blocks() {
+        let deployed = deploy(SOURCE, applies);
+        assert!(deployed.contains("# export LAPTOP=1\n# # # nested comment\n#\n"));
+        assert!(deployed.contains("\nexport DESKTOP=1\n"));
+        assert!(deployed.contains("\n// inner\n// dimport:endif\n// outer\n"));
+        assert!(deployed.starts_with("# a comment\nexport EDITOR=vim\n"));
+    }
+
+    #[test]
+    fn undeploy_restores_source() {
+        let deployed = deploy(SOURCE, applies);
+        assert_eq!(undeploy(&deployed, applies), SOURCE);
+        // applying it again does not change the result
+        let redeployed = deploy(&undeploy(&deployed, applies), applies);
+        assert_eq!(redeployed, deployed);
+    }
+}
@@ -1,14 +1,26 @@
-use crate::util::repository_fetch;
-use crate::CONFIG_PATH;
-use crate::REPOSITORY_DIR;
+use crate::config_path;
+use crate::importer::blocks;
+use crate::importer::encryption::{self, strip_encrypted, ENCRYPTED_EXTENSION};
+use crate::importer::hooks::Hook;
+use crate::importer::manifest::{DeployMode, Manifest, MANIFEST_FILE};
+use crate::importer::scan::ALLOWLIST_FILE;
+use crate::importer::schema::{
+    find_key, validate_mapping_source, validate_mapping_target, Key, KEYS,
+};
+use crate::importer::template::{strip_template, TEMPLATE_EXTENSION};
+use crate::repository_dir;
+use crate::util::{expand_path, hostname, repository_fetch, split_variant, strip_variant};
 use git2::Repository;
 use log::debug;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
 use std::error::Error;
 use std::io::BufReader;
 use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::{
     fs::{self, File},
     io,
@@ -19,6 +31,54 @@ pub struct Config {
     pub home_path: PathBuf,
     pub private_key_path: PathBuf,
     pub ignore_files: Vec<PathBuf>,
+    pub link_style: LinkStyle,
+    /// Repository directories that are linked as a whole, relative to the repository
+    pub linked_dirs: Vec<PathBuf>,
+    /// Mappings from config and manifest with expanded targets
+    pub mappings: Vec<Mapping>,
+    pub manifest: Manifest,
+    pub host: String,
+    /// Active profiles, files tagged with other profiles are not deployed
+    pub profiles: Vec<String>,
+    /// User defined template variables
+    pub variables: HashMap<String, String>,
+    /// Key used for encrypted files
+    pub encryption_key_path: Option<PathBuf>,
+    /// Hooks from config and manifest
+    pub hooks: Vec<Hook>,
+}
+
+/// Deploy a repository subtree somewhere else than the same path in home
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Mapping {
+    /// Relative to the repository
+    pub source: PathBuf,
+    /// Can start with `~` and contain environment variables like `$XDG_CONFIG_HOME`
+    pub target: PathBuf,
+}
+
+/// How symlinks from home to the repository are created
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkStyle {
+    #[default]
+    Absolute,
+    Relative,
+}
+
+impl FromStr for LinkStyle {
+    type Err = String;
+
+    fn from_str(style: &str) -> Result<LinkStyle, String> {
+        match style {
+            "absolute" => Ok(LinkStyle::Absolute),
+            "relative" => Ok(LinkStyle::Relative),
+            _ => Err(format!(
+                "Invalid link style {:?}, use absolute or relative",
+                style
+            )),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -27,29 +87,37 @@ struct UnserializedConfig {
     home_path: String,
     private_key_path: String,
     ignore_files: Vec<String>,
+    #[serde(default)]
+    link_style: LinkStyle,
+    #[serde(default)]
+    linked_dirs: Vec<String>,
+    #[serde(default)]
+    mappings: Vec<Mapping>,
+    #[serde(default)]
+    profiles: Vec<String>,
+    #[serde(default)]
+    variables: HashMap<String, String>,
+    #[serde(default)]
+    encryption_key_path: Option<String>,
+    #[serde(default)]
+    hooks: Vec<Hook>,
 }
 
 impl Config {
     pub fn from_settings() -> Result<Config, Box<dyn Error>> {
-        if let Some(dir_path) = Path::new(CONFIG_PATH).parent() {
+        if let Some(dir_path) = config_path().parent() {
             fs::create_dir_all(dir_path)?;
         }
-        let file = match File::open(CONFIG_PATH) {
+        let file = match File::open(config_path()) {
             Ok(file) => file,
             Err(_) => {
-                let default_config = UnserializedConfig {
-                    repository: String::new(),
-                    home_path: String::new(),
-                    private_key_path: String::new(),
-                    ignore_files: vec![
-                        "README.md".to_string(),
-                        ".gitignore".to_string(),
-                        ".git".to_string(),
-                    ],
-                };
+                let default_config: serde_json::Map<String, Value> = KEYS
+                    .iter()
+                    .map(|key| (key.name.to_string(), key.default_value()))
+                    .collect();
                 let data = serde_json::to_string(&default_config)?;
-                fs::write(CONFIG_PATH, data)?;
-                File::open(CONFIG_PATH)?
+                fs::write(config_path(), data)?;
+                File::open(config_path())?
             }
         };
 
@@ -67,45 +135,142 @@ impl Config {
             return io::Error::new(e.kind(), format!("Invalid Private Key Path: {}", e));
         })?;
 
-        fs::create_dir_all(REPOSITORY_DIR)?;
+        fs::create_dir_all(repository_dir())?;
 
-        let repository = repository_fetch(
-            &uconfig.repository,
-            Path::new(REPOSITORY_DIR),
-            &private_key_path,
-        )?;
+        let repository =
+            repository_fetch(&uconfig.repository, repository_dir(), &private_key_path)?;
 
-        let ignore_files: Vec<PathBuf> = uconfig
+        let mut ignore_files: Vec<PathBuf> = uconfig
             .ignore_files
             .iter()
             .map(|file| repository.workdir().unwrap().join(PathBuf::from(file)))
             .collect();
+        ignore_files.push(repository.workdir().unwrap().join(MANIFEST_FILE));
+        ignore_files.push(repository.workdir().unwrap().join(ALLOWLIST_FILE));
+
+        let manifest = Manifest::load(repository.workdir().unwrap(), &home_path)?;
 
         debug!("Fetched repository");
 
+        let hooks = uconfig
+            .hooks
+            .into_iter()
+            .chain(manifest.hooks.iter().cloned())
+            .collect();
+
         let config = Config {
             repository,
-            home_path,
             private_key_path,
             ignore_files,
+            link_style: uconfig.link_style,
+            linked_dirs: uconfig.linked_dirs.iter().map(PathBuf::from).collect(),
+            mappings: expand_mappings(&uconfig.mappings, &manifest.mappings, &home_path),
+            home_path,
+            manifest,
+            host: hostname(),
+            profiles: uconfig.profiles,
+            variables: uconfig.variables,
+            encryption_key_path: uconfig.encryption_key_path.map(PathBuf::from),
+            hooks,
         };
 
         Ok(config)
     }
 
-    /// Only when config is broken, there is no proper validation
-    pub fn write(property: &str, value: &str) -> Result<(), Box<dyn Error>> {
-        let config_file = File::open(CONFIG_PATH)?;
+    /// Value of a key in config.json, the default when it is not set
+    pub fn get_key(name: &str) -> Result<Value, Box<dyn Error>> {
+        let key = find_key(name)?;
+        let data = Config::read_raw()?;
+        Ok(data
+            .get(key.name)
+            .cloned()
+            .unwrap_or_else(|| key.default_value()))
+    }
+
+    /// Parse and validate value according to the schema of the key and write it
+    pub fn set_key(name: &str, value: &str) -> Result<Value, Box<dyn Error>> {
+        let key = find_key(name)?;
+        let value = key.parse(value)?;
+        Config::write_key(key, value.clone())?;
+        Ok(value)
+    }
+
+    /// Reset a key to its default, keys without a valid default can not be unset
+    pub fn unset_key(name: &str) -> Result<Value, Box<dyn Error>> {
+        let key = find_key(name)?;
+        let value = key.unset_value()?;
+        Config::write_key(key, value.clone())?;
+        Ok(value)
+    }
+
+    /// Every key with its current value
+    pub fn list_keys() -> Result<Vec<(&'static Key, Value)>, Box<dyn Error>> {
+        let data = Config::read_raw()?;
+        Ok(KEYS
+            .iter()
+            .map(|key| {
+                let value = data
+                    .get(key.name)
+                    .cloned()
+                    .unwrap_or_else(|| key.default_value());
+                (key, value)
+            })
+            .collect())
+    }
+
+    /// Validate every key of the config file without loading the repository
+    pub fn check() -> Result<(), Box<dyn Error>> {
+        let data = Config::read_raw()?;
+        let errors: Vec<String> = KEYS
+            .iter()
+            .filter_map(|key| {
+                let value = data
+                    .get(key.name)
+                    .cloned()
+                    .unwrap_or_else(|| key.default_value());
+                key.validate(&value).err()
+            })
+            .collect();
+        if !errors.is_empty() {
+            return Err(errors.join("\n").into());
+        }
+        if let Err(e) = serde_json::from_value::<UnserializedConfig>(data) {
+            return Err(format!("Invalid config: {}", e).into());
+        }
+        Ok(())
+    }
+
+    fn read_raw() -> Result<Value, Box<dyn Error>> {
+        let config_file = File::open(config_path())?;
         let reader = BufReader::new(&config_file);
-        let mut old_data: Value = serde_json::from_reader(reader)?;
-        old_data[property] = Value::String(value.to_string());
-        let new_data = serde_json::to_vec_pretty(&old_data)?;
-        fs::write(CONFIG_PATH, new_data)?;
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Only written when the value is valid and the resulting config can still be loaded
+    pub fn write_key(key: &Key, value: Value) -> Result<(), Box<dyn Error>> {
+        key.validate(&value)?;
+        let mut data = Config::read_raw()?;
+        data[key.name] = value;
+        if let Err(e) = serde_json::from_value::<UnserializedConfig>(data.clone()) {
+            return Err(format!("Invalid config: {}", e).into());
+        }
+        let new_data = serde_json::to_vec_pretty(&data)?;
+        fs::write(config_path(), new_data)?;
         Ok(())
     }
 
+    /// Write a key through the schema like `dimport config set`
+    fn write_setting<T: Serialize>(name: &str, value: &T) -> Result<(), Box<dyn Error>> {
+        Config::write_key(find_key(name)?, serde_json::to_value(value)?)
+    }
+
+    /// Mappings from the config file without the manifest
+    fn config_mappings() -> Result<Vec<Mapping>, Box<dyn Error>> {
+        Ok(serde_json::from_value(Config::get_key("mappings")?)?)
+    }
+
     pub fn show_raw() -> Result<String, Box<dyn Error>> {
-        let config_file = File::open(CONFIG_PATH)?;
+        let config_file = File::open(config_path())?;
         let reader = BufReader::new(&config_file);
         let old_data: Value = serde_json::from_reader(reader)?;
         let pretty_string = serde_json::to_string(&old_data)?;
@@ -113,48 +278,394 @@ impl Config {
     }
 
     pub fn set_home(&mut self, home: &str) -> Result<(), Box<dyn Error>> {
-        let path = PathBuf::from(home);
-        if !path.is_dir() {
-            return Err("Path does not exist or is not a directory".into());
-        }
-        self.home_path = path;
-        let config_file = File::open(CONFIG_PATH)?;
-        let reader = BufReader::new(&config_file);
-        let mut uconfig: UnserializedConfig = serde_json::from_reader(reader)?;
-        uconfig.home_path = home.to_string();
-        let data = serde_json::to_vec_pretty(&uconfig)?;
-        fs::write(CONFIG_PATH, data)?;
+        Config::write_setting("home_path", &home)?;
+        self.home_path = PathBuf::from(home);
+        self.mappings = expand_mappings(
+            &Config::config_mappings()?,
+            &self.manifest.mappings,
+            &self.home_path,
+        );
         Ok(())
     }
 
     pub fn set_private_key(&mut self, private_key: &str) -> Result<(), Box<dyn Error>> {
-        let path = PathBuf::from(private_key);
-        if !path.is_dir() {
-            return Err("Path does not exist or is not a directory".into());
+        Config::write_setting("private_key_path", &private_key)?;
+        self.private_key_path = PathBuf::from(private_key);
+        Ok(())
+    }
+
+    /// Use an existing key or generate a new one when the path does not exist
+    pub fn set_encryption_key(&mut self, key_path: &str) -> Result<(), Box<dyn Error>> {
+        let path = PathBuf::from(key_path);
+        if path.exists() {
+            encryption::load_key(&path)?;
+        } else {
+            encryption::generate_key(&path)?;
         }
-        self.private_key_path = path;
-        let config_file = File::open(CONFIG_PATH)?;
-        let reader = BufReader::new(&config_file);
-        let mut uconfig: UnserializedConfig = serde_json::from_reader(reader)?;
-        uconfig.private_key_path = private_key.to_string();
-        let data = serde_json::to_vec_pretty(&uconfig)?;
-        fs::write(CONFIG_PATH, data)?;
+        Config::write_setting("encryption_key_path", &key_path)?;
+        self.encryption_key_path = Some(path);
         Ok(())
     }
 
-    /// Removes current repository and sets a new one in its place and saves to CONFIG_PATH
-    pub fn set_repository(&mut self, repository_url: &str) -> Result<(), Box<dyn Error>> {
-        let repo_path = Path::new(REPOSITORY_DIR);
-        if repo_path.exists() {
-            fs::remove_dir_all(repo_path)?;
+    pub fn encryption_key(&self) -> Result<Vec<u8>, io::Error> {
+        match &self.encryption_key_path {
+            Some(path) => encryption::load_key(path),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No encryption key set, use `dimport set encryption_key <path>`",
+            )),
         }
-        self.repository = repository_fetch(repository_url, repo_path, &self.private_key_path)?;
-        let config_file = File::open(CONFIG_PATH)?;
-        let reader = BufReader::new(&config_file);
-        let mut uconfig: UnserializedConfig = serde_json::from_reader(reader)?;
-        uconfig.repository = repository_url.to_string();
-        let data = serde_json::to_vec_pretty(&uconfig)?;
-        fs::write(CONFIG_PATH, data)?;
+    }
+
+    pub fn set_link_style(&mut self, style: &str) -> Result<(), Box<dyn Error>> {
+        let link_style: LinkStyle = style.parse()?;
+        Config::write_setting("link_style", &link_style)?;
+        self.link_style = link_style;
+        Ok(())
+    }
+
+    /// Where a repository relative path is deployed to
+    pub fn target_path(&self, relative_path: &Path) -> PathBuf {
+        let mut relative_path = strip_variant(relative_path);
+        if let Some(name) = relative_path.file_name().and_then(|name| name.to_str()) {
+            relative_path = relative_path.with_file_name(deployed_name(name));
+        }
+        let relative_path = &relative_path;
+        let mapping = self
+            .mappings
+            .iter()
+            .filter(|mapping| relative_path.starts_with(&mapping.source))
+            .max_by_key(|mapping| mapping.source.components().count());
+
+        match mapping {
+            Some(mapping) => {
+                let rest = relative_path.strip_prefix(&mapping.source).unwrap();
+                if rest.as_os_str().is_empty() {
+                    mapping.target.clone()
+                } else {
+                    mapping.target.join(rest)
+                }
+            }
+            None => self.home_path.join(relative_path),
+        }
+    }
+
+    /// Repository relative path for a deployed path, None if it can not be deployed from the repository
+    pub fn source_path(&self, target_path: &Path) -> Option<PathBuf> {
+        let mapping = self
+            .mappings
+            .iter()
+            .filter(|mapping| target_path.starts_with(&mapping.target))
+            .max_by_key(|mapping| mapping.target.components().count());
+
+        let relative_path = match mapping {
+            Some(mapping) => mapping
+                .source
+                .join(target_path.strip_prefix(&mapping.target).unwrap()),
+            None => target_path
+                .strip_prefix(&self.home_path)
+                .ok()?
+                .to_path_buf(),
+        };
+
+        // make sure it is not shadowed by a mapping
+        if relative_path.as_os_str().is_empty() || self.target_path(&relative_path) != target_path {
+            return None;
+        }
+        Some(self.variant_path(&relative_path))
+    }
+
+    /// Repository relative path of the variant used on this machine, the path itself if there are no variants
+    /// The path can be the deployed name or a repository name without variant conditions
+    pub fn variant_path(&self, relative_path: &Path) -> PathBuf {
+        let dir = self
+            .repository
+            .workdir()
+            .unwrap()
+            .join(relative_path.parent().unwrap());
+        let names: Vec<String> = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().is_file())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .collect(),
+            Err(_) => return relative_path.to_path_buf(),
+        };
+        // variants are chosen by deployed name, .netrc.enc##host.laptop is a variant of .netrc
+        let name = relative_path.file_name().unwrap().to_string_lossy();
+        match self.choose_variant(deployed_name(&name), &names) {
+            Some(name) => relative_path.with_file_name(name),
+            None => relative_path.to_path_buf(),
+        }
+    }
+
+    /// The file out of names used for base on this machine
+    /// The variant matching the most conditions wins, the plain file is the fallback
+    pub fn choose_variant<'a>(&self, base: &str, names: &'a [String]) -> Option<&'a String> {
+        names
+            .iter()
+            .filter_map(|name| match split_variant(name) {
+                (b, None) if deployed_name(b) == base => Some((name, 0)),
+                (b, Some(conditions)) if deployed_name(b) == base => self
+                    .variant_score(conditions)
+                    .map(|score| (name, score + 1)),
+                _ => None,
+            })
+            .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(name, _)| name)
+    }
+
+    pub fn is_chosen_variant(&self, name: &str, names: &[String]) -> bool {
+        let base = deployed_name(split_variant(name).0);
+        self.choose_variant(base, names)
+            .is_some_and(|chosen| chosen == name)
+    }
+
+    /// Amount of conditions like `host.laptop,profile.hidpi`, None if one does not apply
+    fn variant_score(&self, conditions: &str) -> Option<usize> {
+        let mut score = 0;
+        for condition in conditions.split(',') {
+            let (kind, value) = condition.split_once('.')?;
+            let applies = match kind {
+                "host" => value == self.host,
+                "profile" => self.profiles.iter().any(|profile| profile == value),
+                _ => false,
+            };
+            if !applies {
+                return None;
+            }
+            score += 1;
+        }
+        Some(score)
+    }
+
+    /// Mapping from the arguments of `map` when they are valid
+    pub fn check_mapping(&self, source: &str, target: &str) -> Result<Mapping, Box<dyn Error>> {
+        let mapping = Mapping {
+            source: PathBuf::from(source.trim_end_matches('/')),
+            target: PathBuf::from(target.trim_end_matches('/')),
+        };
+        validate_mapping_source(&mapping.source)?;
+        validate_mapping_target(&mapping.target, &self.home_path)?;
+        Ok(mapping)
+    }
+
+    /// Source of a mapping in the config file, fails when there is none
+    pub fn check_mapped(source: &str) -> Result<PathBuf, Box<dyn Error>> {
+        let source = PathBuf::from(source.trim_end_matches('/'));
+        let mappings = Config::config_mappings()?;
+        if !mappings.iter().any(|m| m.source == source) {
+            return Err(format!("No mapping for {:?}", source).into());
+        }
+        Ok(source)
+    }
+
+    /// Add a mapping and save to the config file
+    pub fn add_mapping(&mut self, source: &str, target: &str) -> Result<(), Box<dyn Error>> {
+        let mapping = self.check_mapping(source, target)?;
+        let mut mappings = Config::config_mappings()?;
+        mappings.retain(|m| m.source != mapping.source);
+        mappings.push(mapping);
+        Config::write_setting("mappings", &mappings)?;
+        self.mappings = expand_mappings(&mappings, &self.manifest.mappings, &self.home_path);
+        Ok(())
+    }
+
+    /// Remove a mapping and save to the config file
+    pub fn remove_mapping(&mut self, source: &str) -> Result<(), Box<dyn Error>> {
+        let source = Config::check_mapped(source)?;
+        let mut mappings = Config::config_mappings()?;
+        mappings.retain(|m| m.source != source);
+        Config::write_setting("mappings", &mappings)?;
+        self.mappings = expand_mappings(&mappings, &self.manifest.mappings, &self.home_path);
         Ok(())
     }
+
+    /// If a repository relative path is deployed on this host with the active profiles
+    pub fn is_selected(&self, relative_path: &Path) -> bool {
+        self.manifest
+            .is_selected(&strip_variant(relative_path), &self.host, &self.profiles)
+    }
+
+    pub fn set_profiles(&mut self, profiles: Vec<String>) -> Result<(), Box<dyn Error>> {
+        Config::write_setting("profiles", &profiles)?;
+        self.profiles = profiles;
+        Ok(())
+    }
+
+    /// If a target path is deployed from a file in the repository
+    pub fn is_deployed(&self, target_path: &Path) -> bool {
+        match self.source_path(target_path) {
+            Some(relative_path) => self
+                .repository
+                .workdir()
+                .unwrap()
+                .join(relative_path)
+                .exists(),
+            None => false,
+        }
+    }
+
+    /// Mode from the manifest, files ending with .tmpl are templates, files ending with .enc are
+    /// encrypted, files containing blocks are copied and everything else is linked
+    pub fn deploy_mode(&self, relative_path: &Path) -> DeployMode {
+        let original_path = relative_path;
+        let relative_path = strip_variant(relative_path);
+        if let Some(mode) = self.manifest.deploy_mode(&relative_path) {
+            return mode;
+        }
+        if let Some(name) = relative_path.file_name() {
+            let name = name.to_string_lossy();
+            if name.ends_with(TEMPLATE_EXTENSION) {
+                return DeployMode::Template;
+            }
+            if name.ends_with(ENCRYPTED_EXTENSION) {
+                return DeployMode::Encrypted;
+            }
+        }
+        if self.has_blocks(&self.repository.workdir().unwrap().join(original_path)) {
+            return DeployMode::Copy;
+        }
+        DeployMode::Link
+    }
+
+    /// If a file contains blocks, only files smaller than 1MB are checked
+    fn has_blocks(&self, path: &Path) -> bool {
+        match path.metadata() {
+            Ok(meta) if meta.is_file() && meta.len() < 1_000_000 => {
+                fs::read_to_string(path).is_ok_and(|content| blocks::has_blocks(&content))
+            }
+            _ => false,
+        }
+    }
+
+    /// Comment out blocks that do not apply to this machine
+    pub fn deploy_blocks(&self, source: &str) -> String {
+        blocks::deploy(source, |conditions| self.block_applies(conditions))
+    }
+
+    /// Map a deployed file with blocks back to its source
+    pub fn undeploy_blocks(&self, deployed: &str) -> String {
+        blocks::undeploy(deployed, |conditions| self.block_applies(conditions))
+    }
+
+    /// If all conditions like `host=laptop profile=work,home` apply to this machine
+    pub fn block_applies(&self, conditions: &str) -> bool {
+        conditions.split_whitespace().all(|condition| {
+            let (kind, values) = match condition.split_once('=') {
+                Some(condition) => condition,
+                None => return false,
+            };
+            let mut values = values.split(',');
+            match kind {
+                "host" => values.any(|value| value == self.host),
+                "profile" => values.any(|value| self.profiles.iter().any(|p| p == value)),
+                _ => false,
+            }
+        })
+    }
+
+    /// Facts about this machine, manifest variables and config variables in increasing precedence
+    pub fn template_variables(&self) -> HashMap<String, String> {
+        let mut variables = HashMap::new();
+        variables.insert("host".to_string(), self.host.clone());
+        variables.insert("os".to_string(), env::consts::OS.to_string());
+        variables.insert("arch".to_string(), env::consts::ARCH.to_string());
+        variables.insert("family".to_string(), env::consts::FAMILY.to_string());
+        variables.insert(
+            "home".to_string(),
+            self.home_path.to_string_lossy().into_owned(),
+        );
+        variables.insert(
+            "user".to_string(),
+            env::var("USER").unwrap_or_else(|_| {
+                self.home_path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            }),
+        );
+        variables.insert("profiles".to_string(), self.profiles.join(","));
+        variables.extend(self.manifest.variables.clone());
+        variables.extend(self.variables.clone());
+        variables
+    }
+
+    pub fn set_variable(&mut self, name: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let mut variables = self.variables.clone();
+        variables.insert(name.to_string(), value.to_string());
+        Config::write_setting("variables", &variables)?;
+        self.variables = variables;
+        Ok(())
+    }
+
+    /// If a repository relative path is inside of a linked directory
+    pub fn in_linked_dir(&self, relative_path: &Path) -> bool {
+        self.linked_dirs
+            .iter()
+            .any(|dir| relative_path.starts_with(dir) && relative_path != dir)
+    }
+
+    /// Link a repository directory as a whole, relative_path is relative to the repository
+    pub fn add_linked_dir(&mut self, relative_path: &str) -> Result<(), Box<dyn Error>> {
+        let path = PathBuf::from(relative_path);
+        if self.linked_dirs.contains(&path) {
+            return Ok(());
+        }
+        let mut linked_dirs = self.linked_dirs.clone();
+        linked_dirs.push(path);
+        Config::write_setting("linked_dirs", &linked_dirs)?;
+        self.linked_dirs = linked_dirs;
+        Ok(())
+    }
+
+    pub fn remove_linked_dir(&mut self, relative_path: &Path) -> Result<(), Box<dyn Error>> {
+        if !self.linked_dirs.iter().any(|dir| dir == relative_path) {
+            return Ok(());
+        }
+        let mut linked_dirs = self.linked_dirs.clone();
+        linked_dirs.retain(|dir| dir != relative_path);
+        Config::write_setting("linked_dirs", &linked_dirs)?;
+        self.linked_dirs = linked_dirs;
+        Ok(())
+    }
+
+    /// Use the clone of repository_url in the repository directory and save it to the config file
+    pub fn set_repository(&mut self, repository_url: &str) -> Result<(), Box<dyn Error>> {
+        self.repository =
+            repository_fetch(repository_url, repository_dir(), &self.private_key_path)?;
+        self.manifest = Manifest::load(self.repository.workdir().unwrap(), &self.home_path)?;
+        Config::write_setting("repository", &repository_url)?;
+        self.mappings = expand_mappings(
+            &Config::config_mappings()?,
+            &self.manifest.mappings,
+            &self.home_path,
+        );
+        Ok(())
+    }
+}
+
+/// Mappings from config take precedence over the manifest
+fn expand_mappings(
+    mappings: &[Mapping],
+    manifest_mappings: &[Mapping],
+    home_path: &Path,
+) -> Vec<Mapping> {
+    let manifest_mappings = manifest_mappings
+        .iter()
+        .filter(|mapping| !mappings.iter().any(|m| m.source == mapping.source));
+
+    mappings
+        .iter()
+        .chain(manifest_mappings)
+        .map(|mapping| Mapping {
+            source: mapping.source.clone(),
+            target: expand_path(mapping.target.to_str().unwrap(), home_path),
+        })
+        .collect()
+}
+
+/// Name of the deployed file without the .enc and .tmpl extensions
+fn deployed_name(name: &str) -> &str {
+    strip_template(strip_encrypted(name))
 }
@@ -0,0 +1,334 @@
+use crate::importer::encryption::load_key;
+use crate::importer::list::Health;
+use crate::util::{get_signature, repository_check_remote, resolve_symlink};
+use crate::Importer;
+use crate::{backup_dir, config_path, repository_dir, socket_path, state_path};
+use log::info;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+
+/// Permissions the server gives the socket
+const SOCKET_MODE: u32 = 0o766;
+
+/// Problem found by a check
+pub struct Problem {
+    pub description: String,
+    /// Can be repaired by `doctor --fix`
+    pub fixable: bool,
+    pub fixed: bool,
+}
+
+/// Result of a single check
+pub struct Check {
+    pub name: &'static str,
+    pub problems: Vec<Problem>,
+}
+
+impl Check {
+    fn new(name: &'static str) -> Check {
+        Check {
+            name,
+            problems: vec![],
+        }
+    }
+
+    fn problem(&mut self, description: String) {
+        self.problems.push(Problem {
+            description,
+            fixable: false,
+            fixed: false,
+        });
+    }
+
+    /// Add a fixable problem, fix is only run when fixing and its error is reported instead
+    fn fixable<F>(&mut self, description: String, fix: bool, op: F)
+    where
+        F: FnOnce() -> Result<(), Box<dyn std::error::Error>>,
+    {
+        let (description, fixed) = if fix {
+            match op() {
+                Ok(()) => (description, true),
+                Err(e) => (format!("{}, could not fix: {}", description, e), false),
+            }
+        } else {
+            (description, false)
+        };
+        self.problems.push(Problem {
+            description,
+            fixable: true,
+            fixed,
+        });
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.problems.is_empty() {
+            return write!(f, "[Ok] {}", self.name);
+        }
+        write!(f, "[Problem] {}", self.name)?;
+        for problem in self.problems.iter() {
+            let label = if problem.fixed {
+                " (fixed)"
+            } else if problem.fixable {
+                " (fixable with --fix)"
+            } else {
+                ""
+            };
+            write!(f, "\n  {}{}", problem.description, label)?;
+        }
+        Ok(())
+    }
+}
+
+impl Importer {
+    /// Check the directories, config, repository, home links and state
+    /// When fix is set missing links are relinked, stale state is pruned and directories are created
+    pub fn doctor(&mut self, fix: bool) -> Vec<Check> {
+        let checks = vec![
+            check_directories(fix),
+            check_socket(fix),
+            self.check_keys(),
+            self.check_repository(),
+            self.check_links(fix),
+            self.check_state(fix),
+        ];
+        if fix {
+            if let Err(e) = self.state.save() {
+                info!("Could not save state after fixing: {}", e);
+            }
+        }
+        checks
+    }
+
+    fn check_keys(&self) -> Check {
+        let mut check = Check::new("Keys");
+        if !self.config.private_key_path.is_file() {
+            check.problem(format!(
+                "Private key {:?} does not exist",
+                self.config.private_key_path
+            ));
+        }
+        if let Some(key_path) = &self.config.encryption_key_path {
+            if let Err(e) = load_key(key_path) {
+                check.problem(format!("Encryption key {:?}: {}", key_path, e));
+            }
+        }
+        check
+    }
+
+    fn check_repository(&self) -> Check {
+        let mut check = Check::new("Repository");
+        if let Err(e) = get_signature() {
+            check.problem(format!(
+                "No signature, set user.name and user.email in your git config: {}",
+                e
+            ));
+        }
+        if let Err(e) =
+            repository_check_remote(&self.config.repository, &self.config.private_key_path)
+        {
+            check.problem(format!("Remote is unreachable: {}", e));
+        }
+        check
+    }
+
+    fn check_links(&mut self, fix: bool) -> Check {
+        let mut check = Check::new("Home links");
+        let files = match self.list() {
+            Ok(files) => files,
+            Err(e) => {
+                check.problem(format!("Could not list files: {}", e));
+                return check;
+            }
+        };
+        let src = self.config.repository.workdir().unwrap().to_path_buf();
+        let real_src = fs::canonicalize(&src).unwrap_or_else(|_| src.clone());
+
+        for file in files.iter() {
+            let relative_path = file.relative_path.to_string_lossy();
+            match &file.health {
+                Health::Missing => {
+                    check.fixable(format!("{:?} is missing", file.target), fix, || {
+                        Ok(self.link(&relative_path)?)
+                    })
+                }
+                Health::Elsewhere(link) if !link.exists() => check.fixable(
+                    format!(
+                        "{:?} points to {:?} which does not exist",
+                        file.target, link
+                    ),
+                    fix,
+                    || Ok(self.link(&relative_path)?),
+                ),
+                Health::Elsewhere(link) => {
+                    check.problem(format!("{:?} points to {:?}", file.target, link))
+                }
+                Health::Replaced => check.problem(format!(
+                    "{:?} is replaced by a file, add it again or remove it to link {}",
+                    file.target, relative_path
+                )),
+                Health::Drifted => check.problem(format!(
+                    "{:?} was edited, restore it to render {} again",
+                    file.target, relative_path
+                )),
+                _ => {}
+            }
+        }
+
+        // links of files that are no longer in the repository
+        let mut removed = vec![];
+        for target in self.state.mapped_files.iter() {
+            let target = Path::new(target);
+            let is_link = target
+                .symlink_metadata()
+                .is_ok_and(|meta| meta.file_type().is_symlink());
+            if !is_link {
+                continue;
+            }
+            match resolve_symlink(target) {
+                Ok(link)
+                    if !link.exists()
+                        && (link.starts_with(&src) || link.starts_with(&real_src)) =>
+                {
+                    check.fixable(
+                        format!("{:?} points to {:?} which is removed", target, link),
+                        fix,
+                        || Ok(fs::remove_file(target)?),
+                    );
+                    removed.push(target.to_path_buf());
+                }
+                _ => {}
+            }
+        }
+        if fix {
+            self.state
+                .mapped_files
+                .retain(|file| !removed.iter().any(|target| target == Path::new(file)));
+        }
+        check
+    }
+
+    fn check_state(&mut self, fix: bool) -> Check {
+        let mut check = Check::new("State");
+        let src = self.config.repository.workdir().unwrap().to_path_buf();
+        let config = &self.config;
+        let state = &mut self.state;
+        let exists = |path: &Path| path.symlink_metadata().is_ok();
+        // missing targets of repository files are reported as missing links
+        let has_source = |target: &Path| {
+            config
+                .source_path(target)
+                .is_some_and(|relative_path| src.join(relative_path).exists())
+        };
+
+        let stale: Vec<String> = state
+            .suggested_files
+            .iter()
+            .chain(state.mapped_files.iter())
+            .chain(state.rendered.keys())
+            .filter(|path| !exists(Path::new(path)) && !has_source(Path::new(path)))
+            .cloned()
+            .chain(
+                state
+                    .drifted_files
+                    .iter()
+                    .filter(|path| !exists(&src.join(path)))
+                    .cloned(),
+            )
+            .collect();
+        for path in stale.iter() {
+            check.fixable(format!("{} does not exist", path), fix, || {
+                state.suggested_files.retain(|other| other != path);
+                state.mapped_files.retain(|other| other != path);
+                state.rendered.remove(path);
+                state.drifted_files.retain(|other| other != path);
+                Ok(())
+            });
+        }
+
+        let hooks = &config.hooks;
+        let removed_hooks = state
+            .hook_results
+            .iter()
+            .filter(|result| {
+                !hooks
+                    .iter()
+                    .any(|hook| hook.event == result.event && hook.command == result.command)
+            })
+            .count();
+        if removed_hooks > 0 {
+            check.fixable(
+                format!("{} results of removed hooks", removed_hooks),
+                fix,
+                || {
+                    state.hook_results.retain(|result| {
+                        hooks.iter().any(|hook| {
+                            hook.event == result.event && hook.command == result.command
+                        })
+                    });
+                    Ok(())
+                },
+            );
+        }
+        check
+    }
+}
+
+fn check_directories(fix: bool) -> Check {
+    let mut check = Check::new("Directories");
+    let dirs = [
+        config_path().parent(),
+        state_path().parent(),
+        socket_path().parent(),
+        Some(repository_dir()),
+        Some(backup_dir()),
+    ];
+    for dir in dirs.iter().flatten() {
+        if !dir.is_dir() {
+            check.fixable(format!("{:?} does not exist", dir), fix, || {
+                Ok(fs::create_dir_all(dir)?)
+            });
+        }
+    }
+    check
+}
+
+/// The server binds the socket again when it is removed
+fn check_socket(fix: bool) -> Check {
+    let mut check = Check::new("Socket");
+    let meta = match fs::symlink_metadata(socket_path()) {
+        Ok(meta) => meta,
+        Err(e) => {
+            // the server binds it again on its own, there is nothing to fix here
+            check.problem(format!("{}: {}", socket_path().display(), e));
+            return check;
+        }
+    };
+    if !meta.file_type().is_socket() {
+        check.fixable(
+            format!("{} is not a socket", socket_path().display()),
+            fix,
+            || Ok(fs::remove_file(socket_path())?),
+        );
+    } else if meta.permissions().mode() & 0o777 != SOCKET_MODE {
+        check.fixable(
+            format!(
+                "{} has permissions {:o} instead of {:o}",
+                socket_path().display(),
+                meta.permissions().mode() & 0o777,
+                SOCKET_MODE
+            ),
+            fix,
+            || {
+                Ok(fs::set_permissions(
+                    socket_path(),
+                    fs::Permissions::from_mode(SOCKET_MODE),
+                )?)
+            },
+        );
+    }
+    check
+}
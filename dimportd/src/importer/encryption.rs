@@ -0,0 +1,85 @@
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+/// File extension of repository files that are stored encrypted
+pub const ENCRYPTED_EXTENSION: &str = ".enc";
+
+/// Written in front of every encrypted file
+const MAGIC: &[u8] = b"dimport-encrypted-v1\n";
+const NONCE_SIZE: usize = 24;
+
+pub fn strip_encrypted(name: &str) -> &str {
+    name.strip_suffix(ENCRYPTED_EXTENSION).unwrap_or(name)
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Write a new random key, readable only by the owner
+pub fn generate_key(path: &Path) -> Result<(), io::Error> {
+    let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(&key)
+}
+
+/// Read a key written by generate_key
+pub fn load_key(path: &Path) -> Result<Vec<u8>, io::Error> {
+    let key = fs::read(path)?;
+    if key.len() != 32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Encryption key {:?} has to be 32 bytes", path),
+        ));
+    }
+    Ok(key)
+}
+
+/// Write decrypted data to a file only readable by the owner
+pub fn write_private(path: &Path, data: &[u8]) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // mode is only used when the file is created
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(data)
+}
+
+pub fn encrypt(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| io::Error::other("Could not encrypt"))?;
+
+    let mut data = Vec::with_capacity(MAGIC.len() + NONCE_SIZE + ciphertext.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+pub fn decrypt(key: &[u8], data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    if !is_encrypted(data) || data.len() < MAGIC.len() + NONCE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "File is not encrypted by dimport",
+        ));
+    }
+    let (nonce, ciphertext) = data[MAGIC.len()..].split_at(NONCE_SIZE);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    cipher
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Could not decrypt, wrong key?"))
+}
@@ -0,0 +1,174 @@
+use crate::Importer;
+use glob::Pattern;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Command run by `sh -c` in the home directory when event happens
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    pub event: HookEvent,
+    pub command: String,
+    /// Glob pattern relative to the repository, post-link hooks only run for matching files
+    #[serde(default)]
+    pub path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookEvent {
+    PreSync,
+    PostSync,
+    /// After files are deployed or updated, with their targets in `DIMPORT_PATHS`
+    PostLink,
+    /// A failing pre-save hook aborts the commit
+    PreSave,
+}
+
+impl fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HookEvent::PreSync => "pre-sync",
+            HookEvent::PostSync => "post-sync",
+            HookEvent::PostLink => "post-link",
+            HookEvent::PreSave => "pre-save",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Last run of a hook
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HookResult {
+    pub event: HookEvent,
+    pub command: String,
+    pub success: bool,
+    /// Combined stdout and stderr
+    pub output: String,
+}
+
+impl Hook {
+    /// Repository paths out of paths this hook runs for
+    fn matching<'a>(&self, paths: &'a [PathBuf]) -> Vec<&'a PathBuf> {
+        let pattern = match &self.path {
+            Some(pattern) => match Pattern::new(pattern) {
+                Ok(pattern) => Some(pattern),
+                Err(e) => {
+                    error!("Invalid hook path {:?}: {}", pattern, e);
+                    return vec![];
+                }
+            },
+            None => None,
+        };
+        paths
+            .iter()
+            .filter(|path| {
+                pattern.as_ref().is_none_or(|pattern| {
+                    path.ancestors()
+                        .take_while(|path| !path.as_os_str().is_empty())
+                        .any(|path| pattern.matches_path(path))
+                })
+            })
+            .collect()
+    }
+
+    fn run(&self, home_path: &Path, targets: &[PathBuf]) -> HookResult {
+        let targets = targets
+            .iter()
+            .map(|target| target.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let result = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .current_dir(home_path)
+            .env("DIMPORT_PATHS", targets)
+            .output();
+
+        let (success, output) = match result {
+            Ok(output) => {
+                let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+                text.push_str(&String::from_utf8_lossy(&output.stderr));
+                (output.status.success(), text.trim_end().to_string())
+            }
+            Err(e) => (false, format!("Could not run: {}", e)),
+        };
+        HookResult {
+            event: self.event,
+            command: self.command.clone(),
+            success,
+            output,
+        }
+    }
+}
+
+/// A pre hook failed, the operation is not run
+#[derive(Debug)]
+pub struct HookFailed(String);
+
+impl fmt::Display for HookFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for HookFailed {}
+
+impl Importer {
+    /// Run all hooks for event and remember their output
+    /// For post-link hooks paths are the deployed repository paths
+    /// Returns an error when a pre hook failed
+    pub fn run_hooks(&mut self, event: HookEvent, paths: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+        let hooks: Vec<Hook> = self
+            .config
+            .hooks
+            .iter()
+            .filter(|hook| hook.event == event)
+            .cloned()
+            .collect();
+        if hooks.is_empty() {
+            return Ok(());
+        }
+
+        for hook in hooks.iter() {
+            let targets: Vec<PathBuf> = if event == HookEvent::PostLink {
+                let matching = hook.matching(paths);
+                if matching.is_empty() {
+                    continue;
+                }
+                matching
+                    .iter()
+                    .map(|path| self.config.target_path(path))
+                    .collect()
+            } else {
+                vec![]
+            };
+
+            info!("Running {} hook: {}", event, hook.command);
+            let result = hook.run(&self.config.home_path, &targets);
+            if result.success {
+                info!("Hook `{}` succeeded: {}", hook.command, result.output);
+            } else {
+                error!("Hook `{}` failed: {}", hook.command, result.output);
+            }
+
+            let failed = !result.success;
+            self.state
+                .hook_results
+                .retain(|r| r.event != result.event || r.command != result.command);
+            self.state.hook_results.push(result);
+
+            if failed && (event == HookEvent::PreSync || event == HookEvent::PreSave) {
+                self.state.save()?;
+                return Err(HookFailed(format!("{} hook `{}` failed", event, hook.command)).into());
+            }
+        }
+
+        self.state.save()?;
+        Ok(())
+    }
+}
@@ -1,43 +1,137 @@
-use crate::util::find_equal_dir;
-use crate::REPOSITORY_DIR;
-use log::{debug, info};
+use crate::importer::blocks::has_blocks;
+use crate::importer::encryption::{decrypt, write_private};
+use crate::importer::manifest::DeployMode;
+use crate::importer::template::render;
+use crate::repository_dir;
+use crate::util::{
+    copy_dir, find_all_files_symlink, find_equal_dir, links_to, strip_variant, symlink_with_style,
+};
+use log::{debug, error, info};
 
-use crate::{util::find_equal_files, Importer, BACKUP_DIR};
+use crate::{backup_dir, util::find_equal_files, Importer};
 use std::fs;
 use std::io;
 use std::io::Error;
 use std::os::unix::fs::symlink;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+/// Copy target to the backup directory if it exists, returns true if backed up
+fn backup_file(to: &Path, cur: &Path) -> Result<bool, Error> {
+    if !to.exists() {
+        return Ok(false);
+    }
+    let mut backup_path = backup_dir().join(cur);
+    if !backup_path.exists() {
+        fs::create_dir_all(&backup_path)?;
+    }
+    backup_path = backup_path.join(to.file_name().unwrap());
+    debug!("Backing up {:?} {:?}", to, backup_path);
+    if to.is_dir() {
+        copy_dir(to, &backup_path)?;
+    } else {
+        fs::copy(to, backup_path)?;
+    }
+    Ok(true)
+}
 
 impl Importer {
     pub fn backup(&self) -> Result<(), Error> {
         let mut c = 0;
         let backup = |_from: &Path, to: &Path, cur: &Path| {
-            if to.exists() {
-                let mut backup_path = Path::new(BACKUP_DIR).join(cur);
-                if !backup_path.exists() {
-                    fs::create_dir_all(&backup_path)?;
-                }
-                backup_path = backup_path.join(to.file_name().unwrap());
-                debug!("Backing up {:?} {:?}", to, backup_path);
-                fs::copy(to, backup_path)?;
-                c = c + 1;
+            if backup_file(to, cur)? {
+                c += 1;
             }
-
             Ok(())
         };
         self.recurse_with_config(backup)?;
         info!("Backed up {} files", c);
         Ok(())
     }
+    /// Change the active profiles, removes files that are not selected anymore and
+    /// deploys newly selected files
+    pub fn switch_profiles(
+        &mut self,
+        profiles: Vec<String>,
+    ) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+        self.update_copies()?;
+        let old = self.deployed_files()?;
+        self.config.set_profiles(profiles)?;
+        let new = self.deployed_files()?;
+
+        let removed: Vec<_> = old.iter().filter(|file| !new.contains(file)).collect();
+        for (from, to, cur) in removed.iter() {
+            debug!("Removing deselected file {:?}", to);
+            if let Ok(meta) = to.symlink_metadata() {
+                let is_copy = meta.is_file() && self.config.deploy_mode(from) != DeployMode::Link;
+                if !meta.file_type().is_symlink() && !is_copy {
+                    continue;
+                }
+                fs::remove_file(to)?;
+            }
+            let backup_path = backup_dir().join(cur).join(to.file_name().unwrap());
+            if backup_path.is_dir() {
+                copy_dir(&backup_path, to)?;
+            } else if backup_path.exists() {
+                fs::copy(&backup_path, to)?;
+            }
+        }
+
+        let added: Vec<_> = new.iter().filter(|file| !old.contains(file)).collect();
+        for (from, to, cur) in added.iter() {
+            debug!("Deploying newly selected file {:?}", to);
+            backup_file(to, cur)?;
+            self.link(from.to_str().unwrap())?;
+        }
+
+        // blocks in copies can depend on profiles
+        for (from, _, _) in new.iter().filter(|file| old.contains(file)) {
+            if self.config.deploy_mode(from) == DeployMode::Copy {
+                self.link(from.to_str().unwrap())?;
+            }
+        }
+
+        info!(
+            "Switched profiles, removed {} and added {} files",
+            removed.len(),
+            added.len()
+        );
+        Ok((removed.len(), added.len()))
+    }
+    /// All deployed files as (repository relative path, target, relative directory)
+    fn deployed_files(&self) -> Result<Vec<(PathBuf, PathBuf, PathBuf)>, Error> {
+        let src = self.config.repository.workdir().unwrap();
+        let mut files = vec![];
+        self.recurse_with_config(|from: &Path, to: &Path, cur: &Path| {
+            let relative_path = from.strip_prefix(src).unwrap();
+            files.push((
+                relative_path.to_path_buf(),
+                to.to_path_buf(),
+                cur.to_path_buf(),
+            ));
+            Ok(())
+        })?;
+        Ok(files)
+    }
     pub fn link(&self, relative_source_path: &str) -> Result<(), Error> {
+        if self.config.in_linked_dir(Path::new(relative_source_path)) {
+            debug!("{} is linked by its directory", relative_source_path);
+            return Ok(());
+        }
+        let relative_path = Path::new(relative_source_path);
+        if self.config.variant_path(&strip_variant(relative_path)) != relative_path {
+            debug!(
+                "{} is not the variant for this machine",
+                relative_source_path
+            );
+            return Ok(());
+        }
         let src = self
             .config
             .repository
             .workdir()
             .unwrap()
             .join(relative_source_path);
-        let dest = &self.config.home_path.join(relative_source_path);
+        let dest = &self.config.target_path(Path::new(relative_source_path));
 
         // Remove if exists
         if let Ok(meta) = dest.symlink_metadata() {
@@ -51,7 +145,7 @@ impl Importer {
         if !dest.parent().unwrap().exists() {
             fs::create_dir_all(dest.parent().unwrap())?;
         }
-        symlink(src, dest)
+        self.deploy(&src, dest)
     }
     pub fn link_all(&self) -> Result<(), Error> {
         let link = |from: &Path, to: &Path, _cur: &Path| {
@@ -68,11 +162,98 @@ impl Importer {
             if !to.parent().unwrap().exists() {
                 fs::create_dir_all(to.parent().unwrap())?;
             }
-            symlink(from, to)
+            self.deploy_or_skip(from, to)
         };
 
         self.recurse_with_config(&link)
     }
+    /// Deploy a repository file to its target according to its deploy mode
+    pub fn deploy(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        let relative_path = from
+            .strip_prefix(self.config.repository.workdir().unwrap())
+            .unwrap();
+        if from.is_dir() {
+            return symlink_with_style(from, to, self.config.link_style);
+        }
+        // writing through the old link would overwrite the source
+        if self.is_outdated_link(from, to) {
+            fs::remove_file(to)?;
+        }
+        match self.config.deploy_mode(relative_path) {
+            DeployMode::Link => symlink_with_style(from, to, self.config.link_style),
+            DeployMode::Copy => match fs::read_to_string(from) {
+                Ok(source) if has_blocks(&source) => {
+                    fs::write(to, self.config.deploy_blocks(&source))
+                }
+                _ => fs::copy(from, to).map(|_| ()),
+            },
+            DeployMode::Template => fs::write(to, self.render(from)?),
+            DeployMode::Encrypted => {
+                if self.config.encryption_key_path.is_none() {
+                    info!("No encryption key set, not deploying {:?}", from);
+                    return Ok(());
+                }
+                let key = self.config.encryption_key()?;
+                write_private(to, &decrypt(&key, &fs::read(from)?)?)
+            }
+        }
+    }
+    /// Deploy all files but templates that can not be rendered, update_templates reports those
+    pub fn deploy_or_skip(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        match self.deploy(from, to) {
+            Err(e) if e.kind() == io::ErrorKind::InvalidData && self.is_template(from) => {
+                error!("{}, skipping it", e);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+    fn is_template(&self, from: &Path) -> bool {
+        from.strip_prefix(self.config.repository.workdir().unwrap())
+            .is_ok_and(|path| self.config.deploy_mode(path) == DeployMode::Template)
+    }
+    /// If to links to the file from while it should be deployed as a copy, after blocks were added
+    pub fn is_outdated_link(&self, from: &Path, to: &Path) -> bool {
+        let relative_path = from
+            .strip_prefix(self.config.repository.workdir().unwrap())
+            .unwrap();
+        from.is_file()
+            && self.config.deploy_mode(relative_path) != DeployMode::Link
+            && to
+                .symlink_metadata()
+                .is_ok_and(|meta| meta.file_type().is_symlink())
+            && links_to(to, from)
+    }
+    /// Render a repository template with the variables of this machine
+    pub fn render(&self, from: &Path) -> Result<String, Error> {
+        let template = fs::read_to_string(from)?;
+        render(&template, &self.config.template_variables()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Could not render {:?}: {}", from, e),
+            )
+        })
+    }
+    /// Rewrite all links pointing into the repository to the configured link style
+    /// Returns the amount of rewritten links
+    pub fn relink(&self) -> Result<usize, Error> {
+        let mut c = 0;
+        let relink = |from: &Path, to: &Path, _cur: &Path| {
+            if let Ok(meta) = to.symlink_metadata() {
+                if meta.file_type().is_symlink() && links_to(to, from) {
+                    debug!("Relinking {:?} to {:?}", to, from);
+                    fs::remove_file(to)?;
+                    symlink_with_style(from, to, self.config.link_style)?;
+                    c += 1;
+                }
+            }
+            Ok(())
+        };
+
+        self.recurse_with_config(relink)?;
+        info!("Relinked {} files", c);
+        Ok(c)
+    }
     pub fn restore(&self) -> Result<(), Error> {
         info!("Restoring from backup");
         let op = |_from: &Path, to: &Path, _cur: &Path| {
@@ -83,23 +264,34 @@ impl Importer {
                 }
 
                 let mut restore_from_backup = |from: &Path, to: &Path, _cur: &Path| {
-                    fs::copy(from, to)?;
-                    Ok(())
+                    if let Ok(meta) = to.symlink_metadata() {
+                        if meta.file_type().is_symlink() {
+                            fs::remove_file(to)?;
+                        }
+                    }
+                    if !to.parent().unwrap().exists() {
+                        fs::create_dir_all(to.parent().unwrap())?;
+                    }
+                    if from.is_dir() {
+                        copy_dir(from, to)
+                    } else {
+                        fs::copy(from, to).map(|_| ())
+                    }
                 };
 
                 return find_equal_files(
-                    Path::new(BACKUP_DIR),
-                    &self.config.home_path,
+                    backup_dir(),
+                    &self.config,
                     Path::new(""),
-                    &self.config.ignore_files,
                     &mut restore_from_backup,
                 );
             }
-            let backup_path = Path::new(BACKUP_DIR)
-                .join(_cur)
-                .join(to.file_name().unwrap());
+            let backup_path = backup_dir().join(_cur).join(to.file_name().unwrap());
 
-            if backup_path.exists() {
+            if backup_path.is_dir() {
+                debug!("Copying {:?} from backup to {:?}", backup_path, to);
+                copy_dir(&backup_path, to)?;
+            } else if backup_path.exists() {
                 debug!("Copying {:?} from backup to {:?}", backup_path, to);
                 fs::copy(backup_path, to)?;
             }
@@ -110,10 +302,11 @@ impl Importer {
         self.recurse_with_config(&op)
     }
     pub fn intitialize_mapped(&mut self) -> Result<(), Error> {
-        let home = self.config.home_path.clone();
+        let config = &self.config;
+        let state = &mut self.state;
 
-        self.state.mapped_files = vec![];
-        self.state.save()?;
+        state.mapped_files = vec![];
+        state.save()?;
 
         let mut op = |dir: &Path| {
             for entry in fs::read_dir(dir)? {
@@ -121,25 +314,286 @@ impl Importer {
                 if path.is_file() {
                     if let Some(path) = path.to_str() {
                         debug!("Adding {} to Mapped Files", path);
-                        self.state.mapped_files.push(path.to_owned());
+                        state.mapped_files.push(path.to_owned());
                     }
                 }
             }
             Ok(())
         };
 
-        find_equal_dir(Path::new(REPOSITORY_DIR), &home, Path::new(""), &mut op)?;
-        self.state.save()?;
+        find_equal_dir(repository_dir(), config, Path::new(""), &mut op)?;
+        state.save()?;
         Ok(())
     }
 
+    /// Replace a home directory with a single link to the repository directory
+    /// Files only in home are moved into the repository, conflicting files are moved to the backup
+    /// Returns the amount of moved files
+    pub fn link_dir(&mut self, relative_path: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let src = self
+            .config
+            .repository
+            .workdir()
+            .unwrap()
+            .join(relative_path);
+        let dest = self.config.target_path(Path::new(relative_path));
+
+        if let Ok(meta) = dest.symlink_metadata() {
+            if meta.file_type().is_symlink() {
+                if links_to(&dest, &src) {
+                    return Err("Directory is already linked".into());
+                }
+            } else if !meta.is_dir() {
+                return Err(format!("{:?} is not a directory", dest).into());
+            }
+        }
+        if src.exists() && !src.is_dir() {
+            return Err(format!("{:?} is not a directory in the repository", src).into());
+        }
+        fs::create_dir_all(&src)?;
+
+        let mut c = 0;
+        if dest.exists() {
+            let mut op = |path: &Path| {
+                let relative = path.strip_prefix(&dest).unwrap();
+                let repository_path = src.join(relative);
+                let file_type = path.symlink_metadata()?.file_type();
+
+                if file_type.is_symlink() && links_to(path, &repository_path) {
+                    return Ok(());
+                }
+                let new_path = if repository_path.symlink_metadata().is_ok() {
+                    backup_dir().join(relative_path).join(relative)
+                } else {
+                    c += 1;
+                    repository_path
+                };
+                debug!("Moving {:?} to {:?}", path, new_path);
+                fs::create_dir_all(new_path.parent().unwrap())?;
+                if file_type.is_symlink() {
+                    symlink(fs::read_link(path)?, &new_path)?;
+                } else {
+                    fs::copy(path, &new_path)?;
+                }
+                Ok(())
+            };
+            find_all_files_symlink(&dest, &mut op)?;
+            fs::remove_dir_all(&dest)?;
+        } else if !dest.parent().unwrap().exists() {
+            fs::create_dir_all(dest.parent().unwrap())?;
+        }
+
+        symlink_with_style(&src, &dest, self.config.link_style)?;
+        self.config.add_linked_dir(relative_path)?;
+
+        // Files in the directory are now owned by the repository
+        let dest_prefix = format!("{}/", dest.to_str().unwrap());
+        self.state
+            .mapped_files
+            .retain(|file| !file.starts_with(&dest_prefix));
+        self.state
+            .suggested_files
+            .retain(|file| !file.starts_with(&dest_prefix));
+        self.state.save()?;
+
+        info!("Linked directory {:?}, moved {} files", dest, c);
+        Ok(c)
+    }
+
+    /// Stop tracking the deployed files for which matches(relative path, target) is true
+    /// Deployed files become real files and their removal from the repository is staged for the next save
+    /// Returns the forgotten repository paths
+    pub fn forget<F>(&mut self, matches: F) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>>
+    where
+        F: Fn(&Path, &Path) -> bool,
+    {
+        let src = self.config.repository.workdir().unwrap().to_path_buf();
+        let files: Vec<(PathBuf, PathBuf, PathBuf)> = self
+            .deployed_files()?
+            .into_iter()
+            .filter(|(relative_path, to, _)| matches(relative_path, to))
+            .collect();
+
+        let mut index = self.config.repository.index()?;
+        for (relative_path, to, _) in files.iter() {
+            let from = src.join(relative_path);
+            let is_real_file = to
+                .symlink_metadata()
+                .is_ok_and(|meta| !meta.file_type().is_symlink());
+
+            if !is_real_file {
+                if to.symlink_metadata().is_ok() {
+                    fs::remove_file(to)?;
+                }
+                fs::create_dir_all(to.parent().unwrap())?;
+            }
+            if from.is_dir() {
+                if !is_real_file {
+                    copy_dir(&from, to)?;
+                }
+                fs::remove_dir_all(&from)?;
+                index.remove_dir(relative_path, 0)?;
+                self.config.remove_linked_dir(relative_path)?;
+            } else {
+                if !is_real_file {
+                    match self.config.deploy_mode(relative_path) {
+                        DeployMode::Link => fs::copy(&from, to).map(|_| ())?,
+                        _ => self.deploy(&from, to)?,
+                    }
+                }
+                fs::remove_file(&from)?;
+                index.remove_path(relative_path)?;
+            }
+            info!("Forgot {:?}, kept {:?}", relative_path, to);
+
+            // do not suggest it again
+            let target = to.to_string_lossy().into_owned();
+            self.state.suggested_files.retain(|file| *file != target);
+            self.state.rendered.remove(&target);
+            if !self.state.mapped_files.contains(&target) {
+                self.state.mapped_files.push(target);
+            }
+        }
+        index.write()?;
+        self.state.save()?;
+
+        Ok(files
+            .into_iter()
+            .map(|(relative_path, _, _)| relative_path)
+            .collect())
+    }
+
+    /// Move a tracked file or directory from one target to another
+    /// The repository files are moved along and staged as a rename for the next save
+    /// Returns the new repository paths of the moved files
+    pub fn move_path(
+        &mut self,
+        old_target: &Path,
+        new_target: &Path,
+    ) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        let src = self.config.repository.workdir().unwrap().to_path_buf();
+        let old_path = self
+            .config
+            .source_path(old_target)
+            .ok_or("Path is not in home folder or a mapped directory")?;
+        let mut new_path = self
+            .config
+            .source_path(new_target)
+            .ok_or("New path is not in home folder or a mapped directory")?;
+        if !src.join(&old_path).exists() {
+            return Err(format!("{:?} is not tracked", old_target).into());
+        }
+        if new_target.symlink_metadata().is_ok() {
+            return Err(format!("{:?} already exists", new_target).into());
+        }
+        // keep variant conditions and extensions like .gitconfig.tmpl##host.laptop
+        if src.join(&old_path).is_file() {
+            let name = old_path.file_name().unwrap().to_string_lossy();
+            let old_name = old_target.file_name().unwrap().to_string_lossy();
+            let new_name = new_target.file_name().unwrap().to_string_lossy();
+            if let Some(rest) = name.strip_prefix(old_name.as_ref()) {
+                new_path.set_file_name(format!("{}{}", new_name, rest));
+            }
+        }
+        if src.join(&new_path).exists() {
+            return Err(format!("{:?} already exists in the repository", new_path).into());
+        }
+
+        let files: Vec<(PathBuf, PathBuf, PathBuf)> = self
+            .deployed_files()?
+            .into_iter()
+            .filter(|(relative_path, _, _)| relative_path.starts_with(&old_path))
+            .collect();
+        // a file that replaced a link only exists in home
+        for (relative_path, to, _) in files.iter() {
+            let from = src.join(relative_path);
+            let linked =
+                from.is_dir() || self.config.deploy_mode(relative_path) == DeployMode::Link;
+            let is_link = to
+                .symlink_metadata()
+                .is_ok_and(|meta| meta.file_type().is_symlink());
+            if linked && to.symlink_metadata().is_ok() && !(is_link && links_to(to, &from)) {
+                return Err(format!(
+                    "{:?} is not a link to the repository, add it again or move it away first",
+                    to
+                )
+                .into());
+            }
+        }
+
+        // copies can contain changes that are not in the repository yet
+        self.update_copies()?;
+
+        for (_, to, _) in files.iter() {
+            if let Ok(meta) = to.symlink_metadata() {
+                if meta.is_dir() {
+                    fs::remove_dir_all(to)?;
+                } else {
+                    fs::remove_file(to)?;
+                }
+            }
+            self.state.rendered.remove(to.to_string_lossy().as_ref());
+        }
+
+        debug!("Moving {:?} to {:?}", old_path, new_path);
+        fs::create_dir_all(src.join(&new_path).parent().unwrap())?;
+        fs::rename(src.join(&old_path), src.join(&new_path))?;
+
+        let mut index = self.config.repository.index()?;
+        if src.join(&new_path).is_dir() {
+            index.remove_dir(&old_path, 0)?;
+            index.add_all([&new_path].iter(), git2::IndexAddOption::DEFAULT, None)?;
+        } else {
+            index.remove_path(&old_path)?;
+            index.add_path(&new_path)?;
+        }
+        index.write()?;
+
+        let linked_dirs: Vec<PathBuf> = self
+            .config
+            .linked_dirs
+            .iter()
+            .filter(|dir| dir.starts_with(&old_path))
+            .cloned()
+            .collect();
+        for dir in linked_dirs.iter() {
+            self.config.remove_linked_dir(dir)?;
+            let dir = new_path.join(dir.strip_prefix(&old_path).unwrap());
+            self.config.add_linked_dir(dir.to_str().unwrap())?;
+        }
+
+        let mut moved = vec![];
+        for (relative_path, _, _) in files.iter() {
+            let rest = relative_path.strip_prefix(&old_path).unwrap();
+            let relative_path = if rest.as_os_str().is_empty() {
+                new_path.clone()
+            } else {
+                new_path.join(rest)
+            };
+            self.link(relative_path.to_str().unwrap())?;
+
+            let target = self.config.target_path(&relative_path);
+            let target = target.to_string_lossy().into_owned();
+            if !self.state.mapped_files.contains(&target) {
+                self.state.mapped_files.push(target);
+            }
+            moved.push(relative_path);
+        }
+        self.state
+            .mapped_files
+            .retain(|file| !files.iter().any(|(_, to, _)| to == Path::new(file)));
+        self.state.save()?;
+
+        info!("Moved {:?} to {:?}", old_path, new_path);
+        Ok(moved)
+    }
+
     fn recurse_with_config<F>(&self, mut op: F) -> Result<(), Error>
     where
         F: FnMut(&Path, &Path, &Path) -> io::Result<()>,
     {
         let src = self.config.repository.workdir().unwrap();
-        let dest = &self.config.home_path;
 
-        find_equal_files(src, dest, Path::new(""), &self.config.ignore_files, &mut op)
+        find_equal_files(src, &self.config, Path::new(""), &mut op)
     }
 }
@@ -0,0 +1,137 @@
+use crate::importer::manifest::DeployMode;
+use crate::util::{links_to, resolve_symlink};
+use crate::Importer;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// State of the target of a repository path
+#[derive(PartialEq)]
+pub enum Health {
+    /// Symlink to the repository
+    Linked,
+    /// Copy, rendered template or decrypted file
+    Deployed,
+    /// Rendered template of which the output was edited
+    Drifted,
+    Missing,
+    /// A real file where a symlink is expected
+    Replaced,
+    /// Symlink pointing somewhere else
+    Elsewhere(PathBuf),
+    Ignored,
+    /// Not deployed on this machine because of host rules, profiles or variants
+    Filtered,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Health::Linked => write!(f, "Linked"),
+            Health::Deployed => write!(f, "Deployed"),
+            Health::Drifted => write!(f, "Drifted"),
+            Health::Missing => write!(f, "Missing"),
+            Health::Replaced => write!(f, "Replaced by a file"),
+            Health::Elsewhere(path) => write!(f, "Points to {:?}", path),
+            Health::Ignored => write!(f, "Ignored"),
+            Health::Filtered => write!(f, "Filtered out"),
+        }
+    }
+}
+
+pub struct TrackedFile {
+    /// Relative to the repository
+    pub relative_path: PathBuf,
+    pub target: PathBuf,
+    pub health: Health,
+}
+
+impl Importer {
+    /// Every path in the repository with the state of its target
+    /// Ignored, filtered and linked directories are listed as a whole
+    pub fn list(&self) -> Result<Vec<TrackedFile>, io::Error> {
+        let mut files = vec![];
+        self.list_dir(Path::new(""), &mut files)?;
+        files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        Ok(files)
+    }
+
+    /// Tracked file deployed to target, None if it is not tracked
+    pub fn which(&self, target: &Path) -> Result<Option<TrackedFile>, io::Error> {
+        Ok(self.list()?.into_iter().find(|file| {
+            file.target == target
+                || (self.config.linked_dirs.contains(&file.relative_path)
+                    && target.starts_with(&file.target))
+        }))
+    }
+
+    fn list_dir(&self, cur: &Path, files: &mut Vec<TrackedFile>) -> Result<(), io::Error> {
+        let src = self.config.repository.workdir().unwrap();
+        let paths = fs::read_dir(src.join(cur))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<PathBuf>>>()?;
+        let names: Vec<String> = paths
+            .iter()
+            .filter(|path| path.is_file())
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+
+        for path in paths.iter() {
+            let relative_path = path.strip_prefix(src).unwrap().to_path_buf();
+            if relative_path == Path::new(".git") {
+                continue;
+            }
+            let name = path.file_name().unwrap().to_string_lossy();
+            let health = if self.config.ignore_files.contains(path)
+                || self.config.manifest.is_ignored(&relative_path)
+            {
+                Health::Ignored
+            } else if !self.config.is_selected(&relative_path)
+                || (path.is_file() && !self.config.is_chosen_variant(&name, &names))
+            {
+                Health::Filtered
+            } else if path.is_dir() && !self.config.linked_dirs.contains(&relative_path) {
+                self.list_dir(&relative_path, files)?;
+                continue;
+            } else {
+                self.health(path, &relative_path)
+            };
+
+            files.push(TrackedFile {
+                target: self.config.target_path(&relative_path),
+                relative_path,
+                health,
+            });
+        }
+        Ok(())
+    }
+
+    fn health(&self, from: &Path, relative_path: &Path) -> Health {
+        let to = self.config.target_path(relative_path);
+        let meta = match to.symlink_metadata() {
+            Ok(meta) => meta,
+            Err(_) => return Health::Missing,
+        };
+        if meta.file_type().is_symlink() {
+            return match resolve_symlink(&to) {
+                Ok(_) if links_to(&to, from) => Health::Linked,
+                Ok(link) => Health::Elsewhere(link),
+                Err(_) => Health::Missing,
+            };
+        }
+        if from.is_dir() || self.config.deploy_mode(relative_path) == DeployMode::Link {
+            return Health::Replaced;
+        }
+        let relative_path = relative_path.to_string_lossy();
+        if self
+            .state
+            .drifted_files
+            .iter()
+            .any(|file| *file == relative_path)
+        {
+            return Health::Drifted;
+        }
+        Health::Deployed
+    }
+}
@@ -0,0 +1,132 @@
+use crate::importer::config::Mapping;
+use crate::importer::hooks::Hook;
+use crate::importer::schema::{validate_mapping_source, validate_mapping_target};
+use glob::Pattern;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Name of the manifest in the root of the repository
+pub const MANIFEST_FILE: &str = ".dimport.toml";
+
+/// Optional `.dimport.toml` describing how the repository is deployed
+/// so every machine cloning the repository behaves the same
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    /// Glob patterns relative to the repository that are never deployed
+    #[serde(deserialize_with = "deserialize_patterns")]
+    pub ignore: Vec<Pattern>,
+    pub mappings: Vec<Mapping>,
+    pub files: Vec<FileRule>,
+    /// Defaults for template variables
+    pub variables: HashMap<String, String>,
+    pub hooks: Vec<Hook>,
+}
+
+/// Settings for all repository files matching pattern
+/// A pattern matching a directory also applies to everything inside of it
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileRule {
+    #[serde(deserialize_with = "deserialize_pattern")]
+    pub pattern: Pattern,
+    pub mode: Option<DeployMode>,
+    /// Only deploy on these hosts, all hosts if empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Only deploy when one of these profiles is active, all profiles if empty
+    #[serde(default)]
+    pub profiles: Vec<String>,
+}
+
+impl FileRule {
+    pub fn matches(&self, relative_path: &Path) -> bool {
+        relative_path
+            .ancestors()
+            .take_while(|path| !path.as_os_str().is_empty())
+            .any(|path| self.pattern.matches_path(path))
+    }
+
+    fn allows(&self, host: &str, profiles: &[String]) -> bool {
+        (self.hosts.is_empty() || self.hosts.iter().any(|h| h == host))
+            && (self.profiles.is_empty() || self.profiles.iter().any(|p| profiles.contains(p)))
+    }
+}
+
+/// How a repository file ends up at its target
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DeployMode {
+    /// Symlink to the repository
+    Link,
+    /// Copy of the repository file, changes to the copy are copied back on sync
+    Copy,
+    /// Rendered template, changes to the output are reported as drift
+    Template,
+    /// Stored encrypted, decrypted into a private copy and encrypted again on changes
+    Encrypted,
+}
+
+impl Manifest {
+    /// Load from the repository root, an empty manifest if it does not exist
+    /// Mappings are validated like the ones of the config with targets expanded in home
+    pub fn load(repository_path: &Path, home: &Path) -> Result<Manifest, Box<dyn Error>> {
+        let path = repository_path.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+        let data = fs::read_to_string(&path)?;
+        let manifest: Manifest =
+            toml::from_str(&data).map_err(|e| format!("Invalid {}: {}", MANIFEST_FILE, e))?;
+        for mapping in manifest.mappings.iter() {
+            validate_mapping_source(&mapping.source)
+                .and_then(|_| validate_mapping_target(&mapping.target, home))
+                .map_err(|e| format!("Invalid {}: {}", MANIFEST_FILE, e))?;
+        }
+        Ok(manifest)
+    }
+
+    pub fn is_ignored(&self, relative_path: &Path) -> bool {
+        self.ignore
+            .iter()
+            .any(|pattern| pattern.matches_path(relative_path))
+    }
+
+    /// First mode of a file rule matching the path
+    pub fn deploy_mode(&self, relative_path: &Path) -> Option<DeployMode> {
+        self.files
+            .iter()
+            .filter(|rule| rule.matches(relative_path))
+            .find_map(|rule| rule.mode)
+    }
+
+    /// If all rules matching the path allow this host and active profiles
+    pub fn is_selected(&self, relative_path: &Path, host: &str, profiles: &[String]) -> bool {
+        self.files
+            .iter()
+            .filter(|rule| rule.matches(relative_path))
+            .all(|rule| rule.allows(host, profiles))
+    }
+}
+
+fn deserialize_pattern<'de, D>(deserializer: D) -> Result<Pattern, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    Pattern::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_patterns<'de, D>(deserializer: D) -> Result<Vec<Pattern>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let patterns = Vec::<String>::deserialize(deserializer)?;
+    patterns
+        .iter()
+        .map(|pattern| Pattern::new(pattern).map_err(serde::de::Error::custom))
+        .collect()
+}
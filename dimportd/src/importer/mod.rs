@@ -1,5 +1,6 @@
+use crate::server::{shutdown_requested, take_reload};
 use crate::Server;
-use log::info;
+use log::{error, info};
 use notify_rust;
 use notify_rust::{Notification, NotificationHandle};
 use std::{error::Error, io};
@@ -9,16 +10,27 @@ pub struct Importer {
     pub config: Config,
 }
 
+pub mod blocks;
 pub mod config;
+pub mod doctor;
+pub mod encryption;
+pub mod hooks;
 mod link;
+pub mod list;
+pub mod manifest;
+pub mod scan;
+pub mod schema;
 
 mod sync;
+pub use sync::{severity, Severity};
 
 use config::Config;
 
 pub mod state;
 use state::State;
 
+pub mod template;
+
 impl Importer {
     pub fn new() -> Result<Importer, Box<dyn Error>> {
         let state = State::get()?;
@@ -30,14 +42,83 @@ impl Importer {
         Ok(Importer { state, config })
     }
 
-    pub fn listen(&mut self) -> Result<(), Box<dyn Error>> {
-        let server = Server::new()?;
-
+    /// Synchronize every interval and answer the cli in between
+    /// Transient errors are retried sooner, fatal errors pause synchronizing until the
+    /// config is reloaded or a sync from the cli succeeds
+    pub fn listen(&mut self, server: &mut Server) -> Result<(), Box<dyn Error>> {
+        let mut paused = false;
         loop {
-            self.sync_and_notify()?;
+            if paused && self.state.last_error.is_none() {
+                info!("Synchronized from the cli, resuming");
+                paused = false;
+            }
+            if paused {
+                info!("Synchronizing is paused until the last error is fixed");
+            } else if let Err(e) = self.sync_and_notify() {
+                match severity(e.as_ref()) {
+                    Severity::Transient => error!("Could not sync, retrying: {}", e),
+                    Severity::Fatal => {
+                        error!("Could not sync, pausing: {}", e);
+                        paused = true;
+                    }
+                }
+            }
+
+            // the cli keeps working while waiting to retry
+            let wait = self
+                .retry_delay(server.interval())
+                .unwrap_or_else(|| server.interval());
+            server.check_messages_for(self, wait)?;
+            if shutdown_requested() {
+                info!("Shutting down");
+                self.state.save()?;
+                return Ok(());
+            }
+            if take_reload() {
+                match self.reload() {
+                    Ok(()) => {
+                        info!("Reloaded config");
+                        paused = false;
+                    }
+                    Err(e) => error!("Could not reload config: {}", e),
+                }
+            }
+        }
+    }
 
-            server.check_messages_for_300(self)?;
+    /// Load config.json again, files are restored and linked again when their targets changed
+    /// The old config is kept when the new one is invalid
+    pub fn reload(&mut self) -> Result<(), Box<dyn Error>> {
+        Config::check()?;
+        let repository = Config::get_key("repository")?;
+        let current = self
+            .config
+            .repository
+            .find_remote("origin")?
+            .url()
+            .map(String::from);
+        if repository.as_str() != current.as_deref() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The repository changed, use `dimport set repo` to switch repositories",
+            )
+            .into());
         }
+
+        let config = Config::from_settings()?;
+        let relink = config.home_path != self.config.home_path
+            || config.mappings != self.config.mappings
+            || config.linked_dirs != self.config.linked_dirs;
+        if relink {
+            info!("Paths changed, restoring and linking again");
+            self.restore()?;
+            self.config = config;
+            self.state.initialized = false;
+            self.setup()?;
+        } else {
+            self.config = config;
+        }
+        Ok(())
     }
 
     pub fn notify(&self, body: &str) -> notify_rust::error::Result<NotificationHandle> {
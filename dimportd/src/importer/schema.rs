@@ -0,0 +1,276 @@
+use crate::importer::config::{LinkStyle, Mapping};
+use crate::importer::encryption::load_key;
+use crate::importer::hooks::Hook;
+use crate::util::expand_path;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Component, Path};
+
+/// How a value from the command line is turned into json
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    Text,
+    /// Separated by commas or spaces
+    List,
+    /// Written as json
+    Json,
+}
+
+/// A key of config.json
+pub struct Key {
+    pub name: &'static str,
+    pub kind: Kind,
+    /// Json of the value used when the key is not set
+    default: &'static str,
+    pub description: &'static str,
+    validate: fn(&Value) -> Result<(), String>,
+}
+
+/// Every key that can be read and written with `dimport config`
+pub static KEYS: &[Key] = &[
+    Key {
+        name: "repository",
+        kind: Kind::Text,
+        default: r#""""#,
+        description: "Url of the dotfiles repository",
+        validate: validate_repository,
+    },
+    Key {
+        name: "home_path",
+        kind: Kind::Text,
+        default: r#""""#,
+        description: "Directory the dotfiles are deployed to",
+        validate: validate_dir,
+    },
+    Key {
+        name: "private_key_path",
+        kind: Kind::Text,
+        default: r#""""#,
+        description: "Ssh key used to fetch and push the repository",
+        validate: validate_file,
+    },
+    Key {
+        name: "encryption_key_path",
+        kind: Kind::Text,
+        default: "null",
+        description: "Key for encrypted files",
+        validate: validate_encryption_key,
+    },
+    Key {
+        name: "link_style",
+        kind: Kind::Text,
+        default: r#""absolute""#,
+        description: "Create absolute or relative symlinks",
+        validate: validate_as::<LinkStyle>,
+    },
+    Key {
+        name: "profiles",
+        kind: Kind::List,
+        default: "[]",
+        description: "Active profiles, files tagged with other profiles are not deployed",
+        validate: validate_as::<Vec<String>>,
+    },
+    Key {
+        name: "ignore_files",
+        kind: Kind::List,
+        default: r#"["README.md", ".gitignore", ".git"]"#,
+        description: "Repository paths that are never deployed",
+        validate: validate_relative_paths,
+    },
+    Key {
+        name: "linked_dirs",
+        kind: Kind::List,
+        default: "[]",
+        description: "Repository directories that are linked as a whole",
+        validate: validate_relative_paths,
+    },
+    Key {
+        name: "variables",
+        kind: Kind::Json,
+        default: "{}",
+        description: r#"Template variables like {"email": "me@example.com"}"#,
+        validate: validate_as::<HashMap<String, String>>,
+    },
+    Key {
+        name: "mappings",
+        kind: Kind::Json,
+        default: "[]",
+        description: r#"Deploy repository directories elsewhere like [{"source": "config", "target": "~/.config"}]"#,
+        validate: validate_mappings,
+    },
+    Key {
+        name: "hooks",
+        kind: Kind::Json,
+        default: "[]",
+        description: r#"Commands run around sync, link and save like [{"event": "post-sync", "command": "..."}]"#,
+        validate: validate_as::<Vec<Hook>>,
+    },
+];
+
+pub fn find_key(name: &str) -> Result<&'static Key, String> {
+    KEYS.iter()
+        .find(|key| key.name == name)
+        .ok_or_else(|| format!("Unknown config key {:?}, see `dimport config list`", name))
+}
+
+impl Key {
+    pub fn default_value(&self) -> Value {
+        serde_json::from_str(self.default).unwrap()
+    }
+
+    /// Default value when it is allowed to unset the key
+    pub fn unset_value(&self) -> Result<Value, String> {
+        let value = self.default_value();
+        if (self.validate)(&value).is_err() {
+            return Err(format!("{} is required and can not be unset", self.name));
+        }
+        Ok(value)
+    }
+
+    /// Parse a value from the command line and validate it
+    pub fn parse(&self, value: &str) -> Result<Value, String> {
+        let value = match self.kind {
+            Kind::Text => Value::String(value.to_string()),
+            Kind::List => Value::Array(
+                value
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|item| !item.is_empty())
+                    .map(|item| Value::String(item.to_string()))
+                    .collect(),
+            ),
+            Kind::Json => serde_json::from_str(value)
+                .map_err(|e| format!("Invalid json for {}: {}", self.name, e))?,
+        };
+        self.validate(&value)?;
+        Ok(value)
+    }
+
+    pub fn validate(&self, value: &Value) -> Result<(), String> {
+        (self.validate)(value).map_err(|e| format!("Invalid {}: {}", self.name, e))
+    }
+}
+
+fn validate_as<T: DeserializeOwned>(value: &Value) -> Result<(), String> {
+    serde_json::from_value::<T>(value.clone())
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn as_str(value: &Value) -> Result<&str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| "has to be a string".to_string())
+}
+
+fn validate_repository(value: &Value) -> Result<(), String> {
+    if as_str(value)?.is_empty() {
+        return Err("Repository url can not be empty".into());
+    }
+    Ok(())
+}
+
+fn validate_dir(value: &Value) -> Result<(), String> {
+    let path = Path::new(as_str(value)?);
+    if !path.is_absolute() || !path.is_dir() {
+        return Err(format!("{:?} is not an absolute path to a directory", path));
+    }
+    Ok(())
+}
+
+fn validate_file(value: &Value) -> Result<(), String> {
+    let path = Path::new(as_str(value)?);
+    if !path.is_absolute() || !path.is_file() {
+        return Err(format!("{:?} is not an absolute path to a file", path));
+    }
+    Ok(())
+}
+
+fn validate_encryption_key(value: &Value) -> Result<(), String> {
+    if value.is_null() {
+        return Ok(());
+    }
+    validate_file(value)?;
+    load_key(Path::new(as_str(value)?))
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn validate_mappings(value: &Value) -> Result<(), String> {
+    let mappings: Vec<Mapping> =
+        serde_json::from_value(value.clone()).map_err(|e| e.to_string())?;
+    for mapping in mappings.iter() {
+        validate_mapping_source(&mapping.source)?;
+        // home is absolute, it is only known once the config is loaded
+        validate_mapping_target(&mapping.target, Path::new("/"))?;
+    }
+    Ok(())
+}
+
+/// Targets have to be absolute once `~` and variables are expanded
+pub fn validate_mapping_target(target: &Path, home: &Path) -> Result<(), String> {
+    let expanded = expand_path(&target.to_string_lossy(), home);
+    if !expanded.is_absolute() {
+        return Err(format!(
+            "Target {:?} has to be absolute or start with ~ or a variable like $XDG_CONFIG_HOME",
+            target
+        ));
+    }
+    Ok(())
+}
+
+/// Mapping sources are repository directories
+pub fn validate_mapping_source(source: &Path) -> Result<(), String> {
+    if source.is_absolute() {
+        return Err(format!(
+            "Source {:?} has to be relative to the repository",
+            source
+        ));
+    }
+    if source.components().any(|c| c == Component::ParentDir) {
+        return Err(format!("Source {:?} can not leave the repository", source));
+    }
+    Ok(())
+}
+
+fn validate_relative_paths(value: &Value) -> Result<(), String> {
+    let paths: Vec<String> = serde_json::from_value(value.clone()).map_err(|e| e.to_string())?;
+    match paths.iter().find(|path| Path::new(path).is_absolute()) {
+        Some(path) => Err(format!("{:?} has to be relative to the repository", path)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn mapping_sources_stay_in_the_repository() {
+        assert!(validate_mapping_source(Path::new("config/nvim")).is_ok());
+        assert!(validate_mapping_source(Path::new("/etc")).is_err());
+        assert!(validate_mapping_source(Path::new("../etc")).is_err());
+        assert!(validate_mapping_source(Path::new("config/../../etc")).is_err());
+    }
+
+    #[test]
+    fn mapping_targets_are_absolute_after_expanding() {
+        let home = Path::new("/home/user");
+        assert!(validate_mapping_target(Path::new("/etc/nvim"), home).is_ok());
+        assert!(validate_mapping_target(Path::new("~/.config/nvim"), home).is_ok());
+        assert!(validate_mapping_target(Path::new("$HOME/.config/nvim"), home).is_ok());
+        assert!(validate_mapping_target(Path::new(".config/nvim"), home).is_err());
+    }
+
+    #[test]
+    fn mappings_key_validates_every_mapping() {
+        let key = find_key("mappings").unwrap();
+        let valid = json!([{"source": "nvim", "target": "~/.config/nvim"}]);
+        assert!(key.validate(&valid).is_ok());
+        let relative = json!([{"source": "nvim", "target": ".config/nvim"}]);
+        assert!(key.validate(&relative).is_err());
+        let outside = json!([{"source": "../nvim", "target": "~/.config/nvim"}]);
+        assert!(key.validate(&outside).is_err());
+    }
+}
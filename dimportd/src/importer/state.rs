@@ -1,12 +1,16 @@
-use crate::STATE_PATH;
+use crate::importer::hooks::HookResult;
+use crate::state_path;
 use git2;
 use git2::{Delta, StatusEntry};
+use log::{debug, error};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
-use std::io;
-use std::path::Path;
-use std::{fs::File, io::BufReader};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Difference {
@@ -16,7 +20,8 @@ pub struct Difference {
 impl Difference {
     pub fn from_status_entry(entry: StatusEntry) -> Difference {
         let path = entry.path().unwrap().into();
-        let status = match entry.index_to_workdir() {
+        // staged changes like a forgotten file only have a head to index diff
+        let status = match entry.index_to_workdir().or_else(|| entry.head_to_index()) {
             Some(diff) => diff.status(),
             None => Delta::Unreadable,
         };
@@ -32,43 +37,229 @@ impl Difference {
     }
 }
 
+/// Failed synchronization
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SyncError {
+    pub message: String,
+    /// Unix time of the last failure
+    pub time: u64,
+    /// Retried automatically, otherwise synchronizing is paused
+    pub transient: bool,
+    /// Failures in a row
+    pub attempts: u32,
+}
+
+/// Layout of state.json, raise it and add a migration when the layout changes
+pub const STATE_VERSION: u32 = 2;
+
+/// Migrations of the state as json, the first one upgrades from version 0
+const MIGRATIONS: &[fn(&mut Map<String, Value>)] = &[add_unversioned_fields, add_render_errors];
+
 #[derive(Serialize, Deserialize)]
 pub struct State {
+    /// Version of the layout, missing before versions were introduced
+    pub version: u32,
     pub initialized: bool,
     pub picked_differences: Vec<Difference>,
     pub differences: Vec<Difference>,
     pub mapped_files: Vec<String>,
     pub suggested_files: Vec<String>,
+    /// Hash of the last rendered output by target path
+    pub rendered: HashMap<String, String>,
+    /// Repository paths of templates of which the output was edited
+    pub drifted_files: Vec<String>,
+    /// Why templates could not be rendered in the last sync, they are skipped until fixed
+    pub render_errors: Vec<String>,
+    /// Last result of every hook
+    pub hook_results: Vec<HookResult>,
+    /// Unix time of the last synchronization that succeeded
+    pub last_sync: Option<u64>,
+    /// Cleared by the next synchronization that succeeds
+    pub last_error: Option<SyncError>,
+    /// Saved commits that could not be pushed yet, retried on every sync
+    pub push_queued: bool,
 }
 
 impl State {
-    /// Load from .state.json or create if does not exist
+    /// Load from state.json or create if does not exist
+    /// When state.json can not be read the unfinished or last good copy is used
     pub fn get() -> Result<State, Box<dyn Error>> {
-        if let Some(dir_path) = Path::new(STATE_PATH).parent() {
-            fs::create_dir_all(dir_path)?;
-        }
-        match File::open(STATE_PATH) {
-            Ok(file) => {
-                let reader = BufReader::new(file);
-                let state: State = serde_json::from_reader(reader)?;
-                return Ok(state);
-            }
-            Err(_) => {
-                File::create(STATE_PATH)?;
-                let default_state = State {
-                    initialized: false,
-                    picked_differences: vec![],
-                    differences: vec![],
-                    mapped_files: vec![],
-                    suggested_files: vec![],
-                };
-                default_state.save()?;
-                return Ok(default_state);
+        fs::create_dir_all(state_dir())?;
+
+        let mut errors = vec![];
+        for path in [state_path().to_path_buf(), temp_path(), backup_path()] {
+            match State::load(&path) {
+                Ok(state) => {
+                    if path != state_path() {
+                        error!("Recovered state from {:?}", path);
+                        state.save()?;
+                    }
+                    return Ok(state);
+                }
+                Err(e) => match e.downcast_ref::<io::Error>().map(|e| e.kind()) {
+                    Some(io::ErrorKind::NotFound) => {}
+                    // never go back to an older copy
+                    Some(io::ErrorKind::Unsupported) => return Err(e),
+                    _ => {
+                        error!("Could not load {:?}: {}", path, e);
+                        errors.push(format!("{:?}: {}", path, e));
+                    }
+                },
             }
         }
+        if !errors.is_empty() {
+            return Err(format!("Could not load state, {}", errors.join(", ")).into());
+        }
+
+        let default_state = State {
+            version: STATE_VERSION,
+            initialized: false,
+            picked_differences: vec![],
+            differences: vec![],
+            mapped_files: vec![],
+            suggested_files: vec![],
+            rendered: HashMap::new(),
+            drifted_files: vec![],
+            render_errors: vec![],
+            hook_results: vec![],
+            last_sync: None,
+            last_error: None,
+            push_queued: false,
+        };
+        default_state.save()?;
+        Ok(default_state)
+    }
+
+    /// Read a state file and migrate it to the current version
+    fn load(path: &Path) -> Result<State, Box<dyn Error>> {
+        let data = fs::read(path)?;
+        let mut value: Value = serde_json::from_slice(&data)?;
+        let object = value
+            .as_object_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a json object"))?;
+
+        let version = object.get("version").and_then(Value::as_u64).unwrap_or(0);
+        if version > STATE_VERSION as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{:?} has version {} but this dimportd only knows version {}",
+                    path, version, STATE_VERSION
+                ),
+            )
+            .into());
+        }
+        for migrate in MIGRATIONS[version as usize..].iter() {
+            migrate(object);
+        }
+        if version < STATE_VERSION as u64 {
+            debug!("Migrated state from version {}", version);
+        }
+        object.insert("version".into(), STATE_VERSION.into());
+
+        Ok(serde_json::from_value(value)?)
     }
+
+    /// Write to a temporary file and rename it over state.json so a crash never leaves half a file
+    /// The state it replaces is kept as the last good copy
     pub fn save(&self) -> io::Result<()> {
-        let data = serde_json::to_string(&self)?;
-        fs::write(STATE_PATH, data)
+        let data = serde_json::to_vec(&self)?;
+        let mut file = File::create(temp_path())?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+
+        match fs::rename(state_path(), backup_path()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        fs::rename(temp_path(), state_path())?;
+        // make the renames durable
+        File::open(state_dir())?.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Directory of state.json, the working directory for a bare file name
+fn state_dir() -> &'static Path {
+    match state_path().parent() {
+        Some(dir_path) if !dir_path.as_os_str().is_empty() => dir_path,
+        _ => Path::new("."),
+    }
+}
+
+/// State being written
+fn temp_path() -> PathBuf {
+    sibling_path(".tmp")
+}
+
+/// State before the last save
+fn backup_path() -> PathBuf {
+    sibling_path(".bak")
+}
+
+fn sibling_path(suffix: &str) -> PathBuf {
+    let mut name = state_path().file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    state_path().with_file_name(name)
+}
+
+/// Fields added before the state was versioned
+fn add_unversioned_fields(state: &mut Map<String, Value>) {
+    let defaults = [
+        ("rendered", json!({})),
+        ("drifted_files", json!([])),
+        ("hook_results", json!([])),
+        ("last_sync", Value::Null),
+        ("last_error", Value::Null),
+        ("push_queued", json!(false)),
+    ];
+    for (name, value) in defaults {
+        state.entry(name).or_insert(value);
+    }
+}
+
+/// Version 2 reports templates that could not be rendered
+fn add_render_errors(state: &mut Map<String, Value>) {
+    state.entry("render_errors").or_insert(json!([]));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_state(name: &str, data: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("dimport-test-{}-{}.json", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn load_migrates_unversioned_state() {
+        let path = write_state(
+            "unversioned",
+            r#"{"initialized": true, "picked_differences": [], "differences": [],
+            "mapped_files": ["/home/user/.vimrc"], "suggested_files": []}"#,
+        );
+        let state = State::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(state.version, STATE_VERSION);
+        assert!(state.initialized);
+        assert_eq!(state.mapped_files, vec!["/home/user/.vimrc"]);
+        assert!(state.rendered.is_empty());
+        assert!(state.render_errors.is_empty());
+        assert!(state.last_error.is_none());
+        assert!(!state.push_queued);
+    }
+
+    #[test]
+    fn load_refuses_newer_versions() {
+        let path = write_state("newer", r#"{"version": 999}"#);
+        let error = State::load(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let kind = error.downcast_ref::<io::Error>().map(|e| e.kind());
+        assert_eq!(kind, Some(io::ErrorKind::Unsupported));
     }
 }
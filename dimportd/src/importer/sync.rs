@@ -1,17 +1,81 @@
 use crate::{
-    importer::state::Difference,
-    util::{find_equal_dir, repository_update},
-    REPOSITORY_DIR,
+    importer::{
+        blocks::has_blocks,
+        encryption::{decrypt, encrypt},
+        hooks::HookEvent,
+        hooks::HookFailed,
+        manifest::DeployMode,
+        state::{Difference, SyncError},
+    },
+    repository_dir,
+    util::{
+        find_equal_dir, hash, now, repository_ahead_behind, repository_push, repository_update,
+    },
 };
-use std::path::Path;
-use std::{error::Error, os::unix::fs::symlink};
-use std::{fs, io};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fs, io, iter};
 
-use log::{debug, info};
+use log::{debug, error, info};
 
 use crate::util::find_equal_files;
 use crate::Importer;
 
+/// Delay before the first retry of a failed synchronization
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Whether a failed synchronization is worth retrying
+#[derive(PartialEq, Debug)]
+pub enum Severity {
+    /// Network, authentication or a failed hook, might work next time
+    Transient,
+    /// Needs to be fixed by the user
+    Fatal,
+}
+
+/// Look through the error and its sources for a cause that might go away on its own
+pub fn severity(error: &(dyn Error + 'static)) -> Severity {
+    let transient = iter::successors(Some(error), |&e| e.source()).any(|e| {
+        if let Some(e) = e.downcast_ref::<git2::Error>() {
+            matches!(
+                e.class(),
+                git2::ErrorClass::Net
+                    | git2::ErrorClass::Ssh
+                    | git2::ErrorClass::Http
+                    | git2::ErrorClass::Ssl
+                    | git2::ErrorClass::Os
+                    | git2::ErrorClass::Callback
+            ) || matches!(
+                e.code(),
+                git2::ErrorCode::Auth | git2::ErrorCode::Certificate | git2::ErrorCode::Locked
+            )
+        } else if let Some(e) = e.downcast_ref::<io::Error>() {
+            matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::NetworkUnreachable
+                    | io::ErrorKind::HostUnreachable
+                    | io::ErrorKind::AddrNotAvailable
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::WouldBlock
+            )
+        } else {
+            e.is::<HookFailed>() || e.is::<notify_rust::error::Error>()
+        }
+    });
+    if transient {
+        Severity::Transient
+    } else {
+        Severity::Fatal
+    }
+}
+
 impl Importer {
     /// Synchronize and notify if new changes and save to state
     pub fn sync_and_notify(&mut self) -> Result<(), Box<dyn Error>> {
@@ -28,21 +92,82 @@ impl Importer {
                     self.state.suggested_files.len()
                 ));
             }
-            self.notify(&body)?;
+            // a missing notification daemon should not stop synchronizing
+            if let Err(e) = self.notify(&body) {
+                error!("Could not notify: {}", e);
+            }
         }
 
         Ok(())
     }
 
+    /// Synchronize and remember when it succeeded or why it failed
+    /// Return true if there are new changed files
+    pub fn sync(&mut self) -> Result<bool, Box<dyn Error>> {
+        let result = self.synchronize();
+        match &result {
+            Ok(_) => {
+                self.state.last_sync = Some(now());
+                self.state.last_error = None;
+            }
+            Err(e) => {
+                let attempts = self.state.last_error.as_ref().map_or(0, |e| e.attempts);
+                self.state.last_error = Some(SyncError {
+                    message: e.to_string(),
+                    time: now(),
+                    transient: severity(e.as_ref()) == Severity::Transient,
+                    attempts: attempts + 1,
+                });
+            }
+        }
+        if let Err(e) = self.state.save() {
+            error!("Could not save state: {}", e);
+        }
+        result
+    }
+
+    /// Push saved commits, the push stays queued until it succeeds
+    pub fn push(&mut self) -> Result<(), Box<dyn Error>> {
+        self.state.push_queued = true;
+        self.state.save()?;
+        // pushed some other way
+        if let Ok((0, _)) = repository_ahead_behind(&self.config.repository) {
+            self.state.push_queued = false;
+            return Ok(self.state.save()?);
+        }
+
+        repository_push(&self.config.repository, &self.config.private_key_path)?;
+        info!("Pushed commits");
+        self.state.push_queued = false;
+        Ok(self.state.save()?)
+    }
+
+    /// Time to wait before retrying a transient error, doubles with every failure
+    pub fn retry_delay(&self, interval: Duration) -> Option<Duration> {
+        let error = self.state.last_error.as_ref().filter(|e| e.transient)?;
+        let delay = RETRY_DELAY.saturating_mul(2u32.saturating_pow(error.attempts - 1));
+        Some(delay.min(interval))
+    }
+
     /// Remove files if link removed
     /// Update Suggested files
     /// Return true if there are new changed files
-    pub fn sync(&mut self) -> Result<bool, Box<dyn Error>> {
+    fn synchronize(&mut self) -> Result<bool, Box<dyn Error>> {
         info!("Synchronizing..");
+        self.run_hooks(HookEvent::PreSync, &[])?;
         self.link_removed()?;
+        self.update_copies()?;
         repository_update(&self.config.repository, &self.config.private_key_path)?;
+        // stays queued for the next sync, the rest of the sync does not depend on it
+        if self.state.push_queued {
+            if let Err(e) = self.push() {
+                error!("Could not push queued commits: {}", e);
+            }
+        }
         self.update_suggested()?;
-        self.link_newly_added()?;
+        let linked = self.link_newly_added()?;
+        self.run_hooks(HookEvent::PostLink, &linked)?;
+        self.update_templates()?;
 
         let statuses = self.config.repository.statuses(None)?;
 
@@ -52,6 +177,9 @@ impl Importer {
         let mut new_differences = vec![];
         'outer: for status in statuses.iter() {
             if let Some(path) = status.path() {
+                if !self.config.is_selected(Path::new(path)) {
+                    continue;
+                }
                 for diff in &self.state.differences {
                     if diff.path == path {
                         differences.push(diff.clone());
@@ -76,6 +204,8 @@ impl Importer {
             self.state.differences.append(&mut new_differences);
         }
         self.state.save()?;
+        drop(statuses);
+        self.run_hooks(HookEvent::PostSync, &[])?;
 
         Ok(has_changes)
     }
@@ -83,55 +213,201 @@ impl Importer {
     /// if symlink removed -> remove file from repository
     fn link_removed(&self) -> Result<(), io::Error> {
         let src = self.config.repository.workdir().unwrap();
-        let dest = &self.config.home_path;
 
         let mut op = |from: &Path, to: &Path, _cur: &Path| {
             if let Ok(meta) = to.symlink_metadata() {
-                if !meta.file_type().is_symlink() {
-                    info!("Symlink removed, removing: {:?}", from);
-                    fs::remove_file(from)?;
+                if meta.file_type().is_symlink() {
+                    return Ok(());
                 }
+                let relative_path = from.strip_prefix(src).unwrap();
+                if meta.is_file() && self.config.deploy_mode(relative_path) != DeployMode::Link {
+                    return Ok(());
+                }
+            }
+            // rendered again in update_templates
+            let mode = self.config.deploy_mode(from.strip_prefix(src).unwrap());
+            if mode == DeployMode::Template {
+                return Ok(());
+            }
+            // not deployed without a key
+            if mode == DeployMode::Encrypted && self.config.encryption_key_path.is_none() {
+                return Ok(());
+            }
+            info!("Symlink removed, removing: {:?}", from);
+            if from.is_dir() {
+                fs::remove_dir_all(from)
             } else {
-                info!("Symlink removed, removing: {:?}", from);
-                fs::remove_file(from)?;
+                fs::remove_file(from)
+            }
+        };
+
+        find_equal_files(src, &self.config, Path::new(""), &mut op)
+    }
+
+    /// Copy changes made to deployed copies back into the repository
+    /// Post-link hooks run for the updated files like for newly deployed ones
+    pub fn update_copies(&mut self) -> Result<(), Box<dyn Error>> {
+        let src = self.config.repository.workdir().unwrap().to_path_buf();
+
+        let mut updated = vec![];
+        let mut op = |from: &Path, to: &Path, _cur: &Path| {
+            let relative_path = from.strip_prefix(&src).unwrap();
+            // a link is the source itself, it is turned into a copy by link_newly_added
+            if !to.symlink_metadata().is_ok_and(|meta| meta.is_file()) {
+                return Ok(());
+            }
+            match self.config.deploy_mode(relative_path) {
+                DeployMode::Copy => {}
+                DeployMode::Encrypted => {
+                    if self.update_encrypted(from, to)? {
+                        updated.push(relative_path.to_path_buf());
+                    }
+                    return Ok(());
+                }
+                _ => return Ok(()),
+            }
+            // map edits inside of blocks back to the shared source
+            if let Some(source) = fs::read_to_string(from).ok().filter(|s| has_blocks(s)) {
+                let deployed = fs::read_to_string(to)?;
+                if self.config.deploy_blocks(&source) != deployed {
+                    info!("Copy with blocks changed, updating: {:?}", from);
+                    fs::write(from, self.config.undeploy_blocks(&deployed))?;
+                    updated.push(relative_path.to_path_buf());
+                }
+                return Ok(());
+            }
+            if fs::read(from)? != fs::read(to)? {
+                info!("Copy changed, updating: {:?}", from);
+                fs::copy(to, from)?;
+                updated.push(relative_path.to_path_buf());
             }
             Ok(())
         };
 
-        find_equal_files(src, dest, Path::new(""), &self.config.ignore_files, &mut op)
+        find_equal_files(&src, &self.config, Path::new(""), &mut op)?;
+        self.run_hooks(HookEvent::PostLink, &updated)
+    }
+
+    /// Encrypt a decrypted copy again when its plaintext changed, returns if it changed
+    /// Unchanged files are not encrypted again so they do not show up as changed
+    fn update_encrypted(&self, from: &Path, to: &Path) -> Result<bool, io::Error> {
+        if self.config.encryption_key_path.is_none() {
+            return Ok(false);
+        }
+        let key = self.config.encryption_key()?;
+        let plaintext = fs::read(to)?;
+        if decrypt(&key, &fs::read(from)?)? == plaintext {
+            return Ok(false);
+        }
+        info!("Encrypted file changed, updating: {:?}", from);
+        fs::write(from, encrypt(&key, &plaintext)?)?;
+        Ok(true)
     }
 
-    /// Link files that are newly added to the repository
-    fn link_newly_added(&self) -> Result<(), io::Error> {
+    /// Render templates again when the template or variables changed
+    /// Templates of which the output was edited are marked as drifted instead
+    pub fn update_templates(&mut self) -> Result<(), Box<dyn Error>> {
+        let src = self.config.repository.workdir().unwrap().to_path_buf();
+
+        let mut templates = vec![];
+        let mut op = |from: &Path, to: &Path, _cur: &Path| {
+            let relative_path = from.strip_prefix(&src).unwrap();
+            if self.config.deploy_mode(relative_path) == DeployMode::Template {
+                templates.push((from.to_path_buf(), to.to_path_buf()));
+            }
+            Ok(())
+        };
+        find_equal_files(&src, &self.config, Path::new(""), &mut op)?;
+
+        let mut drifted_files = vec![];
+        let mut rendered_files = vec![];
+        let mut render_errors = vec![];
+        for (from, to) in templates {
+            // one broken template should not stop synchronizing the others
+            let rendered = match self.render(&from) {
+                Ok(rendered) => rendered,
+                Err(e) => {
+                    error!("{}", e);
+                    render_errors.push(e.to_string());
+                    continue;
+                }
+            };
+            let rendered_hash = hash(rendered.as_bytes());
+            let key = to.to_string_lossy().into_owned();
+
+            if let Ok(meta) = to.symlink_metadata() {
+                if meta.file_type().is_symlink() {
+                    fs::remove_file(&to)?;
+                }
+            }
+            match fs::read(&to).ok().map(|data| hash(&data)) {
+                Some(current_hash) if current_hash == rendered_hash => {}
+                Some(current_hash) if self.state.rendered.get(&key) != Some(&current_hash) => {
+                    debug!("Rendered file changed {:?}", to);
+                    let relative_path = from.strip_prefix(&src).unwrap();
+                    drifted_files.push(relative_path.to_string_lossy().into_owned());
+                    continue;
+                }
+                _ => {
+                    info!("Rendering {:?} to {:?}", from, to);
+                    if !to.parent().unwrap().exists() {
+                        fs::create_dir_all(to.parent().unwrap())?;
+                    }
+                    fs::write(&to, &rendered)?;
+                    rendered_files.push(from.strip_prefix(&src).unwrap().to_path_buf());
+                }
+            }
+            self.state.rendered.insert(key, rendered_hash);
+        }
+
+        self.state.drifted_files = drifted_files;
+        self.state.render_errors = render_errors;
+        self.state.save()?;
+        self.run_hooks(HookEvent::PostLink, &rendered_files)?;
+        Ok(())
+    }
+
+    /// Link files that are newly added to the repository and return their repository paths
+    fn link_newly_added(&self) -> Result<Vec<PathBuf>, io::Error> {
         let src = self.config.repository.workdir().unwrap();
-        let dest = &self.config.home_path;
 
+        let mut linked = vec![];
         let mut op = |from: &Path, to: &Path, _cur: &Path| {
             if !to.exists() {
                 info!("New file found {:?}. Linking to {:?}", from, to);
-                symlink(from, to)?;
+                if !to.parent().unwrap().exists() {
+                    fs::create_dir_all(to.parent().unwrap())?;
+                }
+                self.deploy_or_skip(from, to)?;
+                linked.push(from.strip_prefix(src).unwrap().to_path_buf());
+            } else if self.is_outdated_link(from, to) {
+                info!("{:?} is no longer linked, deploying it to {:?}", from, to);
+                self.deploy_or_skip(from, to)?;
+                linked.push(from.strip_prefix(src).unwrap().to_path_buf());
             }
             Ok(())
         };
 
-        find_equal_files(src, dest, Path::new(""), &self.config.ignore_files, &mut op)
+        find_equal_files(src, &self.config, Path::new(""), &mut op)?;
+        Ok(linked)
     }
 
     /// If destination directory has new files add to suggested
     fn update_suggested(&mut self) -> Result<(), io::Error> {
-        let home = self.config.home_path.clone();
+        let config = &self.config;
+        let state = &mut self.state;
 
         let mut op = |dir: &Path| {
             for entry in fs::read_dir(dir)? {
                 let path = entry?.path();
-                if path.is_file() {
+                if path.is_file() && !config.is_deployed(&path) {
                     if let Some(path) = path.to_str() {
                         let path = path.to_owned();
-                        if !self.state.suggested_files.contains(&path)
-                            && !self.state.mapped_files.contains(&path)
+                        if !state.suggested_files.contains(&path)
+                            && !state.mapped_files.contains(&path)
                         {
                             debug!("Adding {} to Suggested Files", path);
-                            self.state.suggested_files.push(path);
+                            state.suggested_files.push(path);
                         }
                     }
                 }
@@ -139,8 +415,8 @@ impl Importer {
             Ok(())
         };
 
-        find_equal_dir(Path::new(REPOSITORY_DIR), &home, Path::new(""), &mut op)?;
-        self.state.save()?;
+        find_equal_dir(repository_dir(), config, Path::new(""), &mut op)?;
+        state.save()?;
         Ok(())
     }
 }
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::env;
+
+/// File extension of repository files that are rendered instead of linked
+pub const TEMPLATE_EXTENSION: &str = ".tmpl";
+
+/// Replace every `{{ name }}` with its variable, `{{ env.NAME }}` with the environment variable
+/// and `{{ "text" }}` with the text, so `{{ "{{" }}` writes a literal `{{`
+pub fn render(template: &str, variables: &HashMap<String, String>) -> Result<String, String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let inner = &rest[start + 2..];
+
+        // a string can contain }} itself
+        if let Some(literal) = inner.trim_start().strip_prefix('"') {
+            let quote = literal.find('"').ok_or("Unclosed string")?;
+            rendered.push_str(&literal[..quote]);
+            rest = literal[quote + 1..]
+                .trim_start()
+                .strip_prefix("}}")
+                .ok_or("Expected }} after string")?;
+            continue;
+        }
+
+        let end = inner.find("}}").ok_or("Unclosed {{")?;
+        let name = inner[..end].trim();
+
+        let value = match name.strip_prefix("env.") {
+            Some(env_name) => env::var(env_name)
+                .map_err(|_| format!("Environment variable {} is not set", env_name))?,
+            None => variables
+                .get(name)
+                .cloned()
+                .ok_or(format!("Unknown variable {}", name))?,
+        };
+        rendered.push_str(&value);
+        rest = &inner[end + 2..];
+    }
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+/// Name without the template extension
+pub fn strip_template(name: &str) -> &str {
+    name.strip_suffix(TEMPLATE_EXTENSION).unwrap_or(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> HashMap<String, String> {
+        HashMap::from([("host".to_string(), "laptop".to_string())])
+    }
+
+    #[test]
+    fn render_replaces_variables() {
+        assert_eq!(
+            render("h={{ host }}\n", &variables()).unwrap(),
+            "h=laptop\n"
+        );
+    }
+
+    #[test]
+    fn render_writes_literal_braces() {
+        let template = "{{ \"{{\" }} host }} {{\"}}\"}}";
+        assert_eq!(render(template, &variables()).unwrap(), "{{ host }} }}");
+    }
+
+    #[test]
+    fn render_rejects_unknown_variables_and_unclosed_tags() {
+        assert!(render("{{ nope }}", &variables()).is_err());
+        assert!(render("{{ host", &variables()).is_err());
+        assert!(render("{{ \"{{ }}", &variables()).is_err());
+    }
+}
@@ -1,15 +1,14 @@
 mod server;
-pub use server::Server;
+pub use server::{install_signal_handlers, Server, DEFAULT_INTERVAL};
 
 mod util;
 
+pub mod lock;
+pub mod paths;
+pub use paths::{backup_dir, config_path, repository_dir, socket_path, state_path};
+
 mod importer;
+pub use importer::config::Config;
 pub use importer::Importer;
 
 pub const BUFFER_SIZE: usize = 10000;
-
-pub const SOCKET_PATH: &str = env!("SOCKET_PATH");
-pub const CONFIG_PATH: &str = env!("CONFIG_PATH");
-pub const STATE_PATH: &str = env!("STATE_PATH");
-pub const REPOSITORY_DIR: &str = env!("REPOSITORY_DIR");
-pub const BACKUP_DIR: &str = env!("BACKUP_DIR");
@@ -0,0 +1,109 @@
+use crate::{socket_path, state_path, BUFFER_SIZE};
+use log::{debug, info};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time a running instance gets to hand over
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Exclusive lock on the state directory, the locked file contains the pid of the daemon
+/// Released when dropped or when the process exits
+pub struct InstanceLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    /// Lock the state directory, fails with the pid of the running instance when it is locked
+    pub fn acquire() -> io::Result<InstanceLock> {
+        let path = pidfile_path();
+        if let Some(dir_path) = path.parent() {
+            fs::create_dir_all(dir_path)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!(
+                        "dimportd is already running with pid {}, use --replace to take over",
+                        pid.trim()
+                    ),
+                ));
+            }
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+        debug!("Locked {:?}", path);
+        Ok(InstanceLock { file, path })
+    }
+
+    /// Ask the running instance to stop over its socket and wait for its lock
+    pub fn replace() -> io::Result<InstanceLock> {
+        match InstanceLock::acquire() {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            result => return result,
+        }
+
+        info!("Asking the running instance to hand over");
+        request_shutdown().map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Could not ask the running instance to stop: {}", e),
+            )
+        })?;
+
+        let start = Instant::now();
+        loop {
+            match InstanceLock::acquire() {
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        && start.elapsed() < HANDOVER_TIMEOUT =>
+                {
+                    thread::sleep(Duration::from_millis(100))
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // empty before unlocking so a stale pid is never read
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+        debug!("Unlocked {:?}", self.path);
+    }
+}
+
+/// Pidfile next to the state file
+fn pidfile_path() -> PathBuf {
+    state_path().with_file_name("dimportd.pid")
+}
+
+fn request_shutdown() -> io::Result<()> {
+    let mut stream = UnixStream::connect(socket_path())?;
+    let mut request = b"shutdown".to_vec();
+    request.resize(BUFFER_SIZE, 0);
+    stream.write_all(&request)?;
+    let mut response = vec![0; BUFFER_SIZE];
+    stream.read_exact(&mut response)?;
+    Ok(())
+}
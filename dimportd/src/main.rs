@@ -1,17 +1,91 @@
-use log::error;
-use std::process;
+use log::{error, info};
+use std::{env, process};
 
-use dimportd::Server;
+use dimportd::lock::InstanceLock;
+use dimportd::{install_signal_handlers, paths, Config, Importer, Server};
+
+mod args;
+use args::{Args, USAGE};
 
 fn main() {
-    env_logger::init();
+    let args: Vec<String> = env::args().collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+    let args = Args::from(args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2)
+    });
+
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = args.log_level {
+        logger.filter_level(level);
+    }
+    logger.init();
+
+    if paths::init(args.paths).is_err() {
+        error!("Paths were already used before they could be set");
+        process::exit(1)
+    }
+
+    if args.check_config {
+        match Config::check() {
+            Ok(()) => println!("Valid config"),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1)
+            }
+        }
+        return;
+    }
+
+    let lock = if args.replace {
+        InstanceLock::replace()
+    } else {
+        InstanceLock::acquire()
+    };
+    let _lock = lock.unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1)
+    });
+
+    if let Err(e) = install_signal_handlers() {
+        error!("Could not install signal handlers: {}", e);
+        process::exit(1)
+    }
 
-    let server = Server::new().unwrap_or_else(|e| {
+    if args.once {
+        if let Err(e) = sync_once() {
+            error!("{}", e);
+            process::exit(1)
+        }
+        return;
+    }
+
+    let mut server = Server::new(args.interval).unwrap_or_else(|e| {
         error!("Could not create server: {}", e);
         process::exit(1)
     });
-    if let Err(e) = server.listen() {
+    let result = server.listen();
+    // exit skips destructors, remove the socket first
+    drop(server);
+    if let Err(e) = result {
         error!("{}", e);
         process::exit(1)
     }
 }
+
+/// Setup when needed and synchronize without listening for the cli
+fn sync_once() -> Result<(), Box<dyn std::error::Error>> {
+    let mut importer = Importer::new()?;
+    importer.setup()?;
+    if importer.sync()? {
+        info!(
+            "{} changed files and {} suggested files",
+            importer.state.differences.len() + importer.state.picked_differences.len(),
+            importer.state.suggested_files.len()
+        );
+    }
+    Ok(())
+}
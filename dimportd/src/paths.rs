@@ -0,0 +1,106 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Files and directories of the daemon, resolved once when first used
+pub struct Paths {
+    pub socket: PathBuf,
+    pub config: PathBuf,
+    pub state: PathBuf,
+    pub repository: PathBuf,
+    pub backup: PathBuf,
+}
+
+static PATHS: OnceLock<Paths> = OnceLock::new();
+
+impl Paths {
+    /// Resolve every path from its `DIMPORT_*` environment variable,
+    /// the value given while building or the XDG base directories in that order
+    pub fn from_env() -> Paths {
+        let config_home = xdg_dir("XDG_CONFIG_HOME", ".config").join("dimport");
+        let state_home = xdg_dir("XDG_STATE_HOME", ".local/state").join("dimport");
+        let data_home = xdg_dir("XDG_DATA_HOME", ".local/share").join("dimport");
+
+        Paths {
+            socket: resolve("DIMPORT_SOCKET", option_env!("SOCKET_PATH"), default_socket),
+            config: resolve("DIMPORT_CONFIG", option_env!("CONFIG_PATH"), || {
+                config_home.join("config.json")
+            }),
+            state: resolve("DIMPORT_STATE", option_env!("STATE_PATH"), || {
+                state_home.join("state.json")
+            }),
+            repository: resolve(
+                "DIMPORT_REPOSITORY_DIR",
+                option_env!("REPOSITORY_DIR"),
+                || data_home.join("repository"),
+            ),
+            backup: resolve("DIMPORT_BACKUP_DIR", option_env!("BACKUP_DIR"), || {
+                data_home.join("backup")
+            }),
+        }
+    }
+}
+
+/// Use other paths than the ones from the environment, only possible before they are used
+pub fn init(paths: Paths) -> Result<(), Paths> {
+    PATHS.set(paths)
+}
+
+fn paths() -> &'static Paths {
+    PATHS.get_or_init(Paths::from_env)
+}
+
+pub fn socket_path() -> &'static Path {
+    &paths().socket
+}
+
+pub fn config_path() -> &'static Path {
+    &paths().config
+}
+
+pub fn state_path() -> &'static Path {
+    &paths().state
+}
+
+pub fn repository_dir() -> &'static Path {
+    &paths().repository
+}
+
+pub fn backup_dir() -> &'static Path {
+    &paths().backup
+}
+
+fn resolve<F>(var: &str, build_time: Option<&str>, default: F) -> PathBuf
+where
+    F: FnOnce() -> PathBuf,
+{
+    match env::var_os(var) {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => build_time.map(PathBuf::from).unwrap_or_else(default),
+    }
+}
+
+/// XDG base directory, relative values are invalid according to the spec and ignored
+fn xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    match env::var_os(var).map(PathBuf::from) {
+        Some(path) if path.is_absolute() => path,
+        _ => home().join(fallback),
+    }
+}
+
+fn home() -> PathBuf {
+    env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// Sockets are per user in the runtime directory or in /tmp without one
+fn default_socket() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+        Some(path) if path.is_absolute() => path.join("dimport").join("dimportd.socket"),
+        _ => match env::var("USER") {
+            Ok(user) => PathBuf::from(format!("/tmp/dimportd-{}.socket", user)),
+            Err(_) => PathBuf::from("/tmp/dimportd.socket"),
+        },
+    }
+}
@@ -1,18 +1,29 @@
-use crate::util::repository_fetch;
-use crate::util::{differences_to_string, repository_push};
-use crate::util::{repository_commit, repository_commit_all};
-use std::{fs, path::Path};
+use crate::util::find_all_files_symlink;
+use crate::util::{differences_to_string, format_elapsed, repository_ahead_behind};
+use crate::util::{repository_bundle, repository_fetch, repository_local_work};
+use crate::util::{repository_commit, repository_commit_all, repository_push};
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use git2::build::CheckoutBuilder;
-use log::info;
+use git2::{Repository, StatusOptions};
+use log::{error, info};
 use regex::Regex;
+use serde_json::Value;
 
-use crate::Importer;
+use crate::importer::config::Config;
+use crate::importer::encryption::{self, ENCRYPTED_EXTENSION};
+use crate::importer::hooks::HookEvent;
+use crate::importer::manifest::MANIFEST_FILE;
+use crate::importer::scan::{Scanner, ALLOWLIST_FILE, ALLOW_MARKER};
+use crate::importer::schema::find_key;
+use crate::{repository_dir, Importer};
 
 /// Sync and return status
 pub fn status(importer: &mut Importer) -> Result<String, String> {
+    // shown as last error below, the status of the previous sync is still useful
     if let Err(e) = importer.sync() {
-        return Err(format!("Could not sync: {}", e));
+        error!("Could not sync: {}", e);
     };
 
     let mut result = String::new();
@@ -41,14 +52,87 @@ pub fn status(importer: &mut Importer) -> Result<String, String> {
             .state
             .suggested_files
             .iter()
-            .map(|file| file.strip_prefix(&home_prefix).unwrap().to_string())
+            .map(|file| file.strip_prefix(&home_prefix).unwrap_or(file).to_string())
             .collect::<Vec<String>>()
             .join("\n");
         result.push_str("Suggested Files\n");
         result.push_str(&suggested_files);
     }
+    if !importer.state.drifted_files.is_empty() {
+        if !result.is_empty() {
+            result.push_str("\n\n");
+        }
+        result.push_str("Drifted Files (rendered output was edited, restore to render again)\n");
+        result.push_str(&importer.state.drifted_files.join("\n"));
+    }
+    if !importer.state.render_errors.is_empty() {
+        if !result.is_empty() {
+            result.push_str("\n\n");
+        }
+        result.push_str("Templates (skipped until they render)\n");
+        result.push_str(&importer.state.render_errors.join("\n"));
+    }
+    if !importer.state.hook_results.is_empty() {
+        if !result.is_empty() {
+            result.push_str("\n\n");
+        }
+        result.push_str("Hooks\n");
+        let hook_results = importer
+            .state
+            .hook_results
+            .iter()
+            .map(|hook| {
+                let kind = if hook.success { "Ok" } else { "Failed" };
+                let mut line = format!("[{}] {}: {}", kind, hook.event, hook.command);
+                // only the end of the output, the full output is in the daemon log
+                let lines: Vec<&str> = hook.output.lines().collect();
+                for output in &lines[lines.len().saturating_sub(5)..] {
+                    line.push_str("\n    ");
+                    line.push_str(output);
+                }
+                line
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        result.push_str(&hook_results);
+    }
     if result.len() == 0 {
-        return Ok("Everything is up to date and no suggestions".into());
+        result.push_str("Everything is up to date and no suggestions");
+    }
+
+    result.push_str("\n\nLast successful sync: ");
+    match importer.state.last_sync {
+        Some(time) => result.push_str(&format_elapsed(time)),
+        None => result.push_str("never"),
+    }
+    match repository_ahead_behind(&importer.config.repository) {
+        Ok((ahead, behind)) if ahead > 0 || behind > 0 || importer.state.push_queued => {
+            let queued = if importer.state.push_queued {
+                ", push queued"
+            } else {
+                ""
+            };
+            result.push_str(&format!(
+                "\nRepository: {} commits ahead and {} behind origin/master{}",
+                ahead, behind, queued
+            ));
+        }
+        Ok(_) => {}
+        Err(e) => error!("Could not compare with origin/master: {}", e),
+    }
+    if let Some(error) = &importer.state.last_error {
+        let retry = if error.transient {
+            "retrying"
+        } else {
+            "paused until fixed"
+        };
+        result.push_str(&format!(
+            "\nLast error: {} after {} attempts, {}: {}",
+            format_elapsed(error.time),
+            error.attempts,
+            retry,
+            error.message
+        ));
     }
     Ok(result)
 }
@@ -61,15 +145,61 @@ pub fn config(importer: &Importer) -> Result<String, String> {
                 ignore_files.push_str("\n");
                 ignore_files.push_str(&file.file_name().unwrap().to_string_lossy())
             }
+            let mut mappings = String::new();
+            for mapping in importer.config.mappings.iter() {
+                mappings.push('\n');
+                mappings.push_str(&format!("{:?} -> {:?}", mapping.source, mapping.target))
+            }
+            let manifest = if importer
+                .config
+                .repository
+                .workdir()
+                .unwrap()
+                .join(MANIFEST_FILE)
+                .exists()
+            {
+                MANIFEST_FILE
+            } else {
+                "None"
+            };
+            let mut variables = String::new();
+            for (name, value) in importer.config.variables.iter() {
+                variables.push('\n');
+                variables.push_str(&format!("{} = {}", name, value))
+            }
+            let mut linked_dirs = String::new();
+            for dir in importer.config.linked_dirs.iter() {
+                linked_dirs.push('\n');
+                linked_dirs.push_str(&dir.to_string_lossy())
+            }
 
             let res = format!(
                 r#"
 Repository: {:?}
 Home Path: {:?}
 Private Key Path: {:?}
+Encryption Key Path: {:?}
+Link Style: {:?}
+Host: {}
+Profiles: {}
+Variables: {}
 Ignored Files: {}
+Linked Directories: {}
+Mappings: {}
+Manifest: {}
         "#,
-                url, importer.config.home_path, importer.config.private_key_path, ignore_files
+                url,
+                importer.config.home_path,
+                importer.config.private_key_path,
+                importer.config.encryption_key_path,
+                importer.config.link_style,
+                importer.config.host,
+                importer.config.profiles.join(", "),
+                variables,
+                ignore_files,
+                linked_dirs,
+                mappings,
+                manifest
             );
             return Ok(res);
         }
@@ -77,6 +207,89 @@ Ignored Files: {}
     return Err("Problem resolving repository".into());
 }
 
+/// Value of a single config key as json
+pub fn config_get(name: &str) -> Result<String, String> {
+    match Config::get_key(name) {
+        Ok(value) => Ok(value.to_string()),
+        Err(e) => Err(format!("Could not get {}: {}", name, e)),
+    }
+}
+
+/// Every config key with its value and description
+pub fn config_list() -> Result<String, String> {
+    let keys = match Config::list_keys() {
+        Ok(keys) => keys,
+        Err(e) => return Err(format!("Could not fetch config: {}", e)),
+    };
+    Ok(keys
+        .iter()
+        .map(|(key, value)| format!("{} = {}\n    {}", key.name, value, key.description))
+        .collect::<Vec<String>>()
+        .join("\n"))
+}
+
+/// Validate and apply a config key, unset when value is None
+/// Keys with their own set command are applied the same way
+pub fn config_set(
+    name: &str,
+    value: Option<&str>,
+    importer: &mut Importer,
+) -> Result<String, String> {
+    let key = find_key(name)?;
+    let value = match value {
+        Some(value) => key.parse(value)?,
+        None => key.unset_value()?,
+    };
+
+    match (key.name, &value) {
+        ("repository", Value::String(repo)) => {
+            return set_repository(repo, false, None, false, importer)
+        }
+        ("home_path", Value::String(home)) => return set_home(home, importer),
+        ("private_key_path", Value::String(path)) => return set_private_key(path, importer),
+        ("encryption_key_path", Value::String(path)) => return set_encryption_key(path, importer),
+        ("link_style", Value::String(style)) => return set_link_style(style, importer),
+        ("profiles", Value::Array(profiles)) => {
+            let profiles = profiles
+                .iter()
+                .filter_map(|profile| profile.as_str())
+                .collect::<Vec<&str>>()
+                .join(",");
+            return set_profiles(&profiles, importer);
+        }
+        _ => {}
+    }
+
+    // deployed files depend on mappings and linked directories
+    let setup = key.name == "mappings" || key.name == "linked_dirs";
+    if let Err(e) = Config::write_key(key, value.clone()) {
+        return Err(format!("Could not write {}: {}", key.name, e));
+    }
+    let config = match Config::from_settings() {
+        Ok(config) => config,
+        Err(e) => return Err(format!("Could not load config: {}", e)),
+    };
+    // restored with the old config, nothing is touched before the new one loaded
+    if setup {
+        if let Err(e) = importer.restore() {
+            return Err(relink(importer, format!("Could not restore files: {}", e)));
+        }
+    }
+    importer.config = config;
+
+    if setup {
+        importer.state.initialized = false;
+        if let Err(e) = importer.setup() {
+            return Err(format!("Setting up with new {} failed: {}", key.name, e));
+        }
+    } else if key.name == "variables" {
+        if let Err(e) = importer.update_templates() {
+            return Err(format!("Could not update templates: {}", e));
+        }
+    }
+    Ok(format!("Succesfully set {} to {}", key.name, value))
+}
+
 pub fn sync(importer: &mut Importer) -> Result<String, String> {
     if let Err(e) = importer.sync_and_notify() {
         return Err(format!("Could not sync: {}", e));
@@ -84,36 +297,172 @@ pub fn sync(importer: &mut Importer) -> Result<String, String> {
     Ok("Synchronization succeeded".into())
 }
 
-pub fn set_repository(repo: &str, importer: &mut Importer) -> Result<String, String> {
-    let test_path = Path::new("/tmp/dimport/repo");
-    if test_path.exists() {
-        if let Err(e) = fs::remove_dir_all(test_path) {
-            return Err(e.to_string());
+/// Switch to another repository, local work of the current one is pushed or bundled first
+/// or it is refused unless forced
+/// The old clone is kept until the new repository is set up
+pub fn set_repository(
+    repo: &str,
+    push: bool,
+    bundle: Option<&str>,
+    force: bool,
+    importer: &mut Importer,
+) -> Result<String, String> {
+    let (changed, mut unpushed) = match repository_local_work(&importer.config.repository) {
+        Ok(work) => work,
+        Err(e) => return Err(format!("Could not check for local work: {}", e)),
+    };
+    if push && unpushed > 0 {
+        if let Err(e) = importer.push() {
+            return Err(format!("Could not push repository: {}", e));
+        }
+        unpushed = 0;
+    }
+    if let Some(path) = bundle {
+        if let Err(e) = repository_bundle(&importer.config.repository, Path::new(path)) {
+            return Err(format!("Could not export bundle: {}", e));
         }
+        info!("Exported repository to {}", path);
+        unpushed = 0;
+    }
+    if !force && (changed > 0 || unpushed > 0) {
+        return Err(local_work_error(changed, unpushed));
     }
-    match repository_fetch(repo, test_path, &importer.config.private_key_path) {
-        Ok(_) => {
-            // Reset home to how it was before
-            if let Err(e) = importer.restore() {
-                return Err(format!("Could not restore files: {}", e));
+
+    let new_path = repository_dir().with_file_name("repository.new");
+    let old_path = repository_dir().with_file_name("repository.old");
+    if old_path.exists() {
+        return Err(format!(
+            "{:?} is left from an earlier switch, remove it after checking it has nothing to keep",
+            old_path
+        ));
+    }
+    if new_path.exists() {
+        if let Err(e) = fs::remove_dir_all(&new_path) {
+            return Err(format!("Could not remove {:?}: {}", new_path, e));
+        }
+    }
+    if let Err(e) = repository_fetch(repo, &new_path, &importer.config.private_key_path) {
+        return Err(format!("Could not clone {}: {}", repo, e));
+    }
+
+    // Reset home to how it was before
+    if let Err(e) = importer.restore() {
+        return Err(format!("Could not restore files: {}", e));
+    }
+    let old_repo = match importer.config.repository.find_remote("origin") {
+        Ok(remote) => remote.url().unwrap_or_default().to_string(),
+        Err(e) => return Err(format!("Could not find origin: {}", e)),
+    };
+    if let Err(e) = fs::rename(repository_dir(), &old_path) {
+        return Err(format!("Could not move the current repository: {}", e));
+    }
+    if let Err(e) = fs::rename(&new_path, repository_dir()) {
+        return Err(format!("Could not move the new repository: {}", e));
+    }
+
+    match switch_repository(repo, importer) {
+        Ok(()) => {
+            if let Err(e) = fs::remove_dir_all(&old_path) {
+                return Err(format!("Could not remove the old repository: {}", e));
             }
-            // change current repository
-            if let Err(e) = importer.config.set_repository(repo) {
-                return Err(format!("Could not change repository: {}", e));
+            Ok("Succesfully changed repository".into())
+        }
+        Err(e) => {
+            // put the old clone back and set it up again
+            let result = fs::remove_dir_all(repository_dir())
+                .and_then(|_| fs::rename(&old_path, repository_dir()))
+                .map_err(|e| e.to_string())
+                .and_then(|_| switch_repository(&old_repo, importer));
+            match result {
+                Ok(()) => Err(format!(
+                    "Setting up with new repository failed, kept {}: {}",
+                    old_repo, e
+                )),
+                Err(restore_error) => Err(format!(
+                    "Setting up with new repository failed: {}, could not switch back: {}",
+                    e, restore_error
+                )),
             }
+        }
+    }
+}
 
-            // setup with new repository
-            importer.state.initialized = false;
-            if let Err(e) = importer.state.save() {
-                return Err(format!("Failed saving: {}", e));
+/// Change the repository before the importer could be created, for example when the clone
+/// has local work and a different origin. Local work is pushed or bundled first
+/// or it is refused unless forced, the clone is removed so the next start clones repo
+pub fn set_repository_importless(
+    repo: &str,
+    push: bool,
+    bundle: Option<&str>,
+    force: bool,
+) -> Result<String, String> {
+    let repository = match Repository::open(repository_dir()) {
+        Ok(repository) => Some(repository),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => None,
+        Err(e) => return Err(format!("Could not open repository: {}", e)),
+    };
+    if let Some(repository) = repository {
+        let (changed, mut unpushed) = match repository_local_work(&repository) {
+            Ok(work) => work,
+            Err(e) => return Err(format!("Could not check for local work: {}", e)),
+        };
+        if push && unpushed > 0 {
+            let private_key_path = match Config::get_key("private_key_path") {
+                Ok(Value::String(path)) => PathBuf::from(path),
+                Ok(value) => return Err(format!("Invalid private key path {}", value)),
+                Err(e) => return Err(format!("Could not get private key path: {}", e)),
+            };
+            if let Err(e) = repository_push(&repository, &private_key_path) {
+                return Err(format!("Could not push repository: {}", e));
             }
-            if let Err(e) = importer.setup() {
-                return Err(format!("Setting up with new repository failed: {}", e));
+            unpushed = 0;
+        }
+        if let Some(path) = bundle {
+            if let Err(e) = repository_bundle(&repository, Path::new(path)) {
+                return Err(format!("Could not export bundle: {}", e));
             }
-            return Ok("Succesfully changed repository".into());
+            info!("Exported repository to {}", path);
+            unpushed = 0;
         }
-        Err(e) => return Err(e.to_string()),
-    };
+        if !force && (changed > 0 || unpushed > 0) {
+            return Err(local_work_error(changed, unpushed));
+        }
+        drop(repository);
+        if let Err(e) = fs::remove_dir_all(repository_dir()) {
+            return Err(format!("Could not remove the current repository: {}", e));
+        }
+    }
+    if let Err(e) = Config::set_key("repository", repo) {
+        return Err(format!("Could not write repository: {}", e));
+    }
+    Ok("Succesfully changed repository".into())
+}
+
+fn local_work_error(changed: usize, unpushed: usize) -> String {
+    format!(
+        "The current repository has {} changed files and {} unpushed commits. \
+        Save them with `dimport save`, push with --push, export them with --bundle <path> \
+        or use --force to discard them",
+        changed, unpushed
+    )
+}
+
+/// Setup with the clone of repo in the repository directory
+fn switch_repository(repo: &str, importer: &mut Importer) -> Result<(), String> {
+    if let Err(e) = importer.config.set_repository(repo) {
+        return Err(format!("Could not change repository: {}", e));
+    }
+    importer.state.initialized = false;
+    importer.state.push_queued = false;
+    importer.state.differences = vec![];
+    importer.state.picked_differences = vec![];
+    if let Err(e) = importer.state.save() {
+        return Err(format!("Failed saving: {}", e));
+    }
+    if let Err(e) = importer.setup() {
+        return Err(format!("Setting up failed: {}", e));
+    }
+    Ok(())
 }
 
 pub fn set_home(home: &str, importer: &mut Importer) -> Result<String, String> {
@@ -141,6 +490,111 @@ pub fn set_private_key(private_key_path: &str, importer: &mut Importer) -> Resul
     return Ok("Succesfully changed private key path".into());
 }
 
+pub fn set_encryption_key(key_path: &str, importer: &mut Importer) -> Result<String, String> {
+    if let Err(e) = importer.config.set_encryption_key(key_path) {
+        return Err(format!("Could not set encryption key: {}", e));
+    }
+    // deploy encrypted files that could not be decrypted before
+    if let Err(e) = importer.sync() {
+        return Err(format!("Could not sync: {}", e));
+    }
+
+    Ok("Succesfully changed encryption key".into())
+}
+
+pub fn set_link_style(style: &str, importer: &mut Importer) -> Result<String, String> {
+    if let Err(e) = importer.config.set_link_style(style) {
+        return Err(format!("Could not set link style: {}", e));
+    }
+
+    match importer.relink() {
+        Ok(c) => Ok(format!(
+            "Succesfully changed link style. Rewrote {} links.",
+            c
+        )),
+        Err(e) => Err(format!("Could not rewrite links: {}", e)),
+    }
+}
+
+pub fn set_variable(name: &str, value: &str, importer: &mut Importer) -> Result<String, String> {
+    if let Err(e) = importer.config.set_variable(name, value) {
+        return Err(format!("Could not set variable: {}", e));
+    }
+    if let Err(e) = importer.update_templates() {
+        return Err(format!("Could not update templates: {}", e));
+    }
+
+    Ok(format!("Succesfully set {} and rendered templates", name))
+}
+
+pub fn set_profiles(profiles: &str, importer: &mut Importer) -> Result<String, String> {
+    let profiles = parse_profiles(profiles);
+
+    match importer.switch_profiles(profiles) {
+        Ok((removed, added)) => Ok(format!(
+            "Succesfully switched profiles. Removed {} and deployed {} files.",
+            removed, added
+        )),
+        Err(e) => Err(format!("Could not switch profiles: {}", e)),
+    }
+}
+
+/// Comma separated list of profiles
+pub fn parse_profiles(profiles: &str) -> Vec<String> {
+    profiles
+        .split(',')
+        .filter(|profile| !profile.is_empty())
+        .map(|profile| profile.to_string())
+        .collect()
+}
+
+pub fn map(source: &str, target: &str, importer: &mut Importer) -> Result<String, String> {
+    // checked first so a typo does not leave home unlinked
+    if let Err(e) = importer.config.check_mapping(source, target) {
+        return Err(format!("Could not add mapping: {}", e));
+    }
+    // Reset targets to how they were before
+    if let Err(e) = importer.restore() {
+        return Err(relink(importer, format!("Could not restore files: {}", e)));
+    }
+    if let Err(e) = importer.config.add_mapping(source, target) {
+        return Err(relink(importer, format!("Could not add mapping: {}", e)));
+    }
+
+    importer.state.initialized = false;
+    if let Err(e) = importer.setup() {
+        return Err(format!("Setting up with new mapping failed: {}", e));
+    }
+    Ok(format!("Succesfully mapped {} to {}", source, target))
+}
+
+pub fn unmap(source: &str, importer: &mut Importer) -> Result<String, String> {
+    if let Err(e) = Config::check_mapped(source) {
+        return Err(format!("Could not remove mapping: {}", e));
+    }
+    if let Err(e) = importer.restore() {
+        return Err(relink(importer, format!("Could not restore files: {}", e)));
+    }
+    if let Err(e) = importer.config.remove_mapping(source) {
+        return Err(relink(importer, format!("Could not remove mapping: {}", e)));
+    }
+
+    importer.state.initialized = false;
+    if let Err(e) = importer.setup() {
+        return Err(format!("Setting up without mapping failed: {}", e));
+    }
+    Ok(format!("Succesfully removed mapping for {}", source))
+}
+
+/// Link files again after failing between restoring and setting up, returns the error
+fn relink(importer: &mut Importer, error: String) -> String {
+    importer.state.initialized = false;
+    match importer.setup() {
+        Ok(()) => error,
+        Err(e) => format!("{}, could not link files again: {}", error, e),
+    }
+}
+
 pub fn ignore_all(importer: &mut Importer) -> Result<String, String> {
     importer
         .state
@@ -174,7 +628,7 @@ pub fn ignore_regex(regex: &str, importer: &mut Importer) -> Result<String, Stri
     Ok(format!("Ignored {} suggested files", removed_amount))
 }
 
-pub fn restore(regex: &str, importer: &Importer) -> Result<String, String> {
+pub fn restore(regex: &str, importer: &mut Importer) -> Result<String, String> {
     let regex = Regex::new(regex).unwrap();
     let mut restore_file_paths = vec![];
     let mut builder = CheckoutBuilder::new();
@@ -200,6 +654,30 @@ pub fn restore(regex: &str, importer: &Importer) -> Result<String, String> {
             return Err(format!("Could not link restored files: {}", e));
         }
     }
+    let restored: Vec<PathBuf> = restore_file_paths.iter().map(PathBuf::from).collect();
+    if let Err(e) = importer.run_hooks(HookEvent::PostLink, &restored) {
+        return Err(format!("Could not run hooks: {}", e));
+    }
+
+    // render drifted templates again
+    let mut rendered_file_paths = vec![];
+    importer.state.drifted_files.retain(|file| {
+        if regex.is_match(file) {
+            rendered_file_paths.push(file.clone());
+            false
+        } else {
+            true
+        }
+    });
+    for file in rendered_file_paths.iter() {
+        if let Err(e) = importer.link(file) {
+            return Err(format!("Could not render restored files: {}", e));
+        }
+    }
+    if let Err(e) = importer.update_templates() {
+        return Err(format!("Could not update templates: {}", e));
+    }
+    restore_file_paths.append(&mut rendered_file_paths);
 
     Ok(format!(
         "{}\nRestored {} Files.",
@@ -208,22 +686,187 @@ pub fn restore(regex: &str, importer: &Importer) -> Result<String, String> {
     ))
 }
 
-pub fn add(absolute_src_path_string: &str, importer: &mut Importer) -> Result<String, String> {
-    let home_path_string = importer.config.home_path.to_str().unwrap();
-    if !absolute_src_path_string.starts_with(home_path_string) {
-        return Err("Path is not in home folder".into());
+/// Files larger than this are not added
+const MAX_ADD_SIZE: u64 = 10_000_000;
+
+/// Add files, directories are added recursively and glob patterns are expanded
+/// Unless confirmed only a preview is returned when more than a single file is given
+pub fn add(
+    paths: &[&str],
+    encrypt: bool,
+    confirmed: bool,
+    importer: &mut Importer,
+) -> Result<String, String> {
+    let (files, skipped) = collect_add_files(paths, importer)?;
+    let home_prefix = format!("{}/", importer.config.home_path.to_str().unwrap());
+    let display = |path: &Path| {
+        let path = path.to_string_lossy();
+        path.strip_prefix(&home_prefix).unwrap_or(&path).to_string()
+    };
+    let skipped = skipped
+        .iter()
+        .map(|(path, reason)| format!("{} ({})", display(path), reason))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    if files.is_empty() {
+        return Err(format!("Nothing to add\n{}", skipped));
+    }
+    let single_file = paths.len() == 1 && files.len() == 1 && files[0] == Path::new(paths[0]);
+    if !confirmed && !single_file {
+        let mut preview = format!("Would add {} files\n", files.len());
+        for file in files.iter() {
+            preview.push_str(&display(file));
+            preview.push('\n');
+        }
+        if !skipped.is_empty() {
+            preview.push_str("\nSkipping\n");
+            preview.push_str(&skipped);
+            preview.push('\n');
+        }
+        preview.push_str("\nRun again with --yes to add them");
+        return Ok(preview);
+    }
+
+    let mut added = vec![];
+    for file in files.iter() {
+        match add_file(file, encrypt, importer) {
+            Ok(relative_path) => added.push(relative_path),
+            Err(e) => {
+                return Err(format!(
+                    "{}\nAdded {} files before failing on {:?}",
+                    e,
+                    added.len(),
+                    file
+                ))
+            }
+        }
+    }
+    if let Err(e) = importer.run_hooks(HookEvent::PostLink, &added) {
+        return Err(format!("Could not run hooks: {}", e));
+    }
+    if let Err(e) = importer.state.save() {
+        return Err(format!("Could not save state: {}", e));
+    }
+
+    if single_file {
+        return Ok("Succesfully added path.".into());
+    }
+    Ok(format!("Succesfully added {} files.", added.len()))
+}
+
+/// File that can not be added with the reason
+type Skipped = (PathBuf, &'static str);
+
+/// Files to add out of paths and globs and the skipped files
+fn collect_add_files(
+    paths: &[&str],
+    importer: &Importer,
+) -> Result<(Vec<PathBuf>, Vec<Skipped>), String> {
+    let mut candidates = vec![];
+    for path in paths {
+        if path.contains(&['*', '?', '['][..]) {
+            match glob::glob(path) {
+                Ok(entries) => candidates.extend(entries.filter_map(|entry| entry.ok())),
+                Err(e) => return Err(format!("Invalid pattern {}: {}", path, e)),
+            }
+        } else {
+            candidates.push(PathBuf::from(path));
+        }
+    }
+
+    let mut files = vec![];
+    let mut skipped = vec![];
+    let mut check = |path: &Path| {
+        match add_skip_reason(path, importer) {
+            Some(reason) => skipped.push((path.to_path_buf(), reason)),
+            None => files.push(path.to_path_buf()),
+        }
+        Ok(())
+    };
+    for candidate in candidates.iter() {
+        let result = match candidate.symlink_metadata() {
+            Ok(meta) if meta.is_dir() => find_all_files_symlink(candidate, &mut check),
+            _ => check(candidate),
+        };
+        if let Err(e) = result {
+            return Err(format!("Could not read {:?}: {}", candidate, e));
+        }
+    }
+    files.sort();
+    files.dedup();
+    Ok((files, skipped))
+}
+
+/// Why a file can not be added, None if it can
+fn add_skip_reason(path: &Path, importer: &Importer) -> Option<&'static str> {
+    let meta = match path.symlink_metadata() {
+        Ok(meta) => meta,
+        Err(_) => return Some("does not exist"),
+    };
+    if meta.file_type().is_symlink() {
+        return Some("symlink");
+    }
+    if !meta.is_file() {
+        return Some("not a regular file");
+    }
+    if meta.len() > MAX_ADD_SIZE {
+        return Some("larger than 10MB");
+    }
+    let relative_path = match importer.config.source_path(path) {
+        Some(relative_path) => relative_path,
+        None => return Some("not in home folder or a mapped directory"),
+    };
+    if importer.config.in_linked_dir(&relative_path) {
+        return Some("in a linked directory");
+    }
+    let repository_path = importer
+        .config
+        .repository
+        .workdir()
+        .unwrap()
+        .join(&relative_path);
+    if relative_path.components().any(|c| c.as_os_str() == ".git")
+        || importer
+            .config
+            .ignore_files
+            .iter()
+            .any(|file| repository_path.starts_with(file))
+        || importer.config.manifest.is_ignored(&relative_path)
+    {
+        return Some("ignored");
+    }
+    if repository_path.exists() {
+        return Some("already in the repository");
+    }
+    None
+}
+
+/// Move a single file into the repository and link it, returns the repository path
+fn add_file(
+    absolute_src_path: &Path,
+    encrypt: bool,
+    importer: &mut Importer,
+) -> Result<PathBuf, String> {
+    let mut relative_path = match importer.config.source_path(absolute_src_path) {
+        Some(relative_path) => relative_path,
+        None => return Err("Path is not in home folder or a mapped directory".into()),
+    };
+    if encrypt {
+        let mut name = relative_path.file_name().unwrap().to_os_string();
+        name.push(ENCRYPTED_EXTENSION);
+        relative_path.set_file_name(name);
     }
-    let relative_path = absolute_src_path_string
-        .strip_prefix(&format!("{}{}", home_path_string, "/"))
-        .unwrap();
     let repository_path = importer
         .config
         .repository
         .workdir()
         .unwrap()
-        .join(relative_path);
+        .join(&relative_path);
 
-    let absolute_src_path = Path::new(absolute_src_path_string);
+    if importer.config.in_linked_dir(&relative_path) {
+        return Err("Path is in a linked directory and already part of the repository".into());
+    }
 
     if !absolute_src_path.exists() {
         return Err(format!("Could not find {:?}", absolute_src_path));
@@ -238,20 +881,33 @@ pub fn add(absolute_src_path_string: &str, importer: &mut Importer) -> Result<St
             ));
         }
     }
-    if let Err(e) = fs::copy(&absolute_src_path, &repository_path) {
+    if encrypt {
+        let encrypted = importer.config.encryption_key().and_then(|key| {
+            fs::read(absolute_src_path).and_then(|data| encryption::encrypt(&key, &data))
+        });
+        match encrypted {
+            Ok(encrypted) => {
+                if let Err(e) = fs::write(&repository_path, encrypted) {
+                    return Err(format!("Could not write encrypted file: {}", e));
+                }
+            }
+            Err(e) => return Err(format!("Could not encrypt file: {}", e)),
+        }
+    } else if let Err(e) = fs::copy(absolute_src_path, &repository_path) {
         return Err(format!("Could not copy file: {}", e));
     }
-    if let Err(e) = fs::remove_file(&absolute_src_path) {
+    if let Err(e) = fs::remove_file(absolute_src_path) {
         return Err(format!("Could not remove source file: {}", e));
     }
-    if let Err(e) = importer.link(relative_path) {
+    if let Err(e) = importer.link(relative_path.to_str().unwrap()) {
         return Err(format!("Could not link file: {}", e));
     }
 
     // Remove from suggested if it exists
+    let absolute_src_path_string = absolute_src_path.to_string_lossy();
     let mut removed_suggested = vec![];
     importer.state.suggested_files.retain(|file_path| {
-        if file_path == absolute_src_path_string {
+        if *file_path == absolute_src_path_string {
             removed_suggested.push(file_path.clone());
             false
         } else {
@@ -261,11 +917,154 @@ pub fn add(absolute_src_path_string: &str, importer: &mut Importer) -> Result<St
 
     importer.state.mapped_files.append(&mut removed_suggested);
 
-    if let Err(e) = importer.state.save() {
-        return Err(format!("Could not save state: {}", e));
+    Ok(relative_path)
+}
+/// Forget tracked files in a home path or with a repository path matching a regex
+pub fn forget(pattern: &str, importer: &mut Importer) -> Result<String, String> {
+    let path = Path::new(pattern);
+    let result = if path.is_absolute() {
+        importer.forget(|_, target| target.starts_with(path))
+    } else {
+        let regex = match Regex::new(pattern) {
+            Ok(regex) => regex,
+            Err(e) => return Err(format!("Invalid regex: {}", e)),
+        };
+        importer.forget(|relative_path, _| regex.is_match(&relative_path.to_string_lossy()))
+    };
+
+    match result {
+        Ok(forgotten) if forgotten.is_empty() => Err("No tracked files found".into()),
+        Ok(forgotten) => Ok(format!(
+            "{}\nForgot {} files, the removal is saved with the next save.",
+            forgotten
+                .iter()
+                .map(|path| path.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            forgotten.len()
+        )),
+        Err(e) => Err(format!("Could not forget files: {}", e)),
+    }
+}
+
+pub fn move_path(old: &str, new: &str, importer: &mut Importer) -> Result<String, String> {
+    let moved = match importer.move_path(Path::new(old), Path::new(new)) {
+        Ok(moved) => moved,
+        Err(e) => return Err(format!("Could not move: {}", e)),
+    };
+    if let Err(e) = importer.run_hooks(HookEvent::PostLink, &moved) {
+        return Err(format!("Could not run hooks: {}", e));
+    }
+
+    Ok(format!(
+        "Succesfully moved {} to {}, relinked {} files. The rename is saved with the next save.",
+        old,
+        new,
+        moved.len()
+    ))
+}
+
+/// List tracked files with the state of their targets, as a flat list or a tree
+pub fn ls(tree: bool, importer: &Importer) -> Result<String, String> {
+    let files = match importer.list() {
+        Ok(files) => files,
+        Err(e) => return Err(format!("Could not list files: {}", e)),
+    };
+    if files.is_empty() {
+        return Ok("No files in the repository".into());
+    }
+
+    let mut result = vec![];
+    let mut printed_dirs: Vec<&Path> = vec![];
+    for file in files.iter() {
+        if !tree {
+            result.push(format!(
+                "[{}] {} -> {}",
+                file.health,
+                file.relative_path.to_string_lossy(),
+                file.target.to_string_lossy()
+            ));
+            continue;
+        }
+
+        let mut dirs: Vec<&Path> = file
+            .relative_path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| !dir.as_os_str().is_empty())
+            .collect();
+        dirs.reverse();
+        for (depth, dir) in dirs.iter().enumerate() {
+            if !printed_dirs.contains(dir) {
+                printed_dirs.push(dir);
+                result.push(format!(
+                    "{}{}/",
+                    "  ".repeat(depth),
+                    dir.file_name().unwrap().to_string_lossy()
+                ));
+            }
+        }
+        result.push(format!(
+            "{}{} [{}]",
+            "  ".repeat(dirs.len()),
+            file.relative_path.file_name().unwrap().to_string_lossy(),
+            file.health
+        ));
+    }
+    Ok(result.join("\n"))
+}
+
+/// Show which repository file is deployed to a home path
+pub fn which(path: &str, importer: &Importer) -> Result<String, String> {
+    match importer.which(Path::new(path)) {
+        Ok(Some(file)) if file.target == Path::new(path) => Ok(format!(
+            "{} [{}]",
+            file.relative_path.to_string_lossy(),
+            file.health
+        )),
+        Ok(Some(file)) => Ok(format!(
+            "Inside linked directory {} [{}]",
+            file.relative_path.to_string_lossy(),
+            file.health
+        )),
+        Ok(None) => Err(format!("{} is not tracked", path)),
+        Err(e) => Err(format!("Could not list files: {}", e)),
+    }
+}
+
+/// Run all health checks and optionally repair what can be repaired
+pub fn doctor(fix: bool, importer: &mut Importer) -> Result<String, String> {
+    let checks = importer.doctor(fix);
+    let result = checks
+        .iter()
+        .map(|check| check.to_string())
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let unfixed = checks
+        .iter()
+        .flat_map(|check| check.problems.iter())
+        .filter(|problem| !problem.fixed)
+        .count();
+    if unfixed > 0 {
+        return Err(format!("{}\n\nFound {} problems", result, unfixed));
     }
+    Ok(result)
+}
+
+pub fn link_dir(absolute_path_string: &str, importer: &mut Importer) -> Result<String, String> {
+    let relative_path = match importer.config.source_path(Path::new(absolute_path_string)) {
+        Some(relative_path) => relative_path,
+        None => return Err("Path is not in home folder or a mapped directory".into()),
+    };
 
-    Ok("Succesfully added path.".into())
+    match importer.link_dir(relative_path.to_str().unwrap()) {
+        Ok(c) => Ok(format!(
+            "Succesfully linked directory. Moved {} files into the repository.",
+            c
+        )),
+        Err(e) => Err(format!("Could not link directory: {}", e)),
+    }
 }
 pub fn pick(regex: &str, importer: &mut Importer) -> Result<String, String> {
     let regex = Regex::new(regex).unwrap();
@@ -308,7 +1107,17 @@ pub fn unpick(regex: &str, importer: &mut Importer) -> Result<String, String> {
     Ok(format!("Unpicked {} files.", len))
 }
 
-pub fn save(description: Option<&str>, importer: &Importer) -> Result<String, String> {
+pub fn save(description: Option<&str>, importer: &mut Importer) -> Result<String, String> {
+    // include the latest edits to copies
+    if let Err(e) = importer.update_copies() {
+        return Err(format!("Could not update copies: {}", e));
+    }
+    if let Err(e) = importer.run_hooks(HookEvent::PreSave, &[]) {
+        return Err(format!("Not saving: {}", e));
+    }
+
+    scan_for_secrets(importer)?;
+
     let description = match description {
         Some(description) => description.to_string(),
         None => differences_to_string(&importer.state.differences),
@@ -332,13 +1141,61 @@ pub fn save(description: Option<&str>, importer: &Importer) -> Result<String, St
 
     info!("Commited");
 
-    if let Err(e) = repository_push(
-        &importer.config.repository,
-        &importer.config.private_key_path,
-    ) {
-        return Err(format!("Could not push repository: {}", e));
+    if let Err(e) = importer.push() {
+        return Ok(format!(
+            "Succesfully saved, the push failed and is retried on every sync: {}",
+            e
+        ));
     }
-    info!("Pushed commit");
 
     Ok("Succesfully saved.".into())
 }
+
+/// Scan the files about to be committed and refuse when they contain possible secrets
+fn scan_for_secrets(importer: &Importer) -> Result<(), String> {
+    let repository = &importer.config.repository;
+    let paths: Vec<String> = if !importer.state.picked_differences.is_empty() {
+        importer
+            .state
+            .picked_differences
+            .iter()
+            .map(|dif| dif.path.clone())
+            .collect()
+    } else {
+        let mut options = StatusOptions::new();
+        options.include_untracked(true).recurse_untracked_dirs(true);
+        match repository.statuses(Some(&mut options)) {
+            Ok(statuses) => statuses
+                .iter()
+                .filter_map(|status| status.path().map(|path| path.to_string()))
+                .collect(),
+            Err(e) => return Err(format!("Could not get changed files: {}", e)),
+        }
+    };
+
+    let workdir = repository.workdir().unwrap();
+    let scanner = match Scanner::new(workdir) {
+        Ok(scanner) => scanner,
+        Err(e) => return Err(format!("Could not create secret scanner: {}", e)),
+    };
+    let mut findings = vec![];
+    for path in paths.iter() {
+        match scanner.scan_file(workdir, path) {
+            Ok(mut file_findings) => findings.append(&mut file_findings),
+            Err(e) => return Err(format!("Could not scan {}: {}", path, e)),
+        }
+    }
+
+    if !findings.is_empty() {
+        let findings = findings
+            .iter()
+            .map(|finding| finding.to_string())
+            .collect::<Vec<String>>()
+            .join("\n");
+        return Err(format!(
+            "Possible secrets found, nothing was saved.\n{}\n\nAdd `{}` to a line or list false positives in {}",
+            findings, ALLOW_MARKER, ALLOWLIST_FILE
+        ));
+    }
+    Ok(())
+}
@@ -1,20 +1,43 @@
-use crate::CONFIG_PATH;
-use crate::{importer::config::Config, Importer};
+use crate::config_path;
+use crate::importer::config::Config;
+use crate::importer::encryption::generate_key;
+use crate::Importer;
 use log::{debug, error, info};
 use std::error::Error;
 use std::os::unix::prelude::PermissionsExt;
 use std::path::Path;
 
-use crate::{BUFFER_SIZE, SOCKET_PATH};
+use crate::{socket_path, BUFFER_SIZE};
 use std::os::unix::net::UnixListener;
 use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use std::{fs, io::prelude::*};
 use std::{io, thread};
 
 mod handlers;
+mod signals;
+pub use signals::{install_signal_handlers, reload_requested, take_reload};
+
+/// Set when the daemon was asked to stop, checked between operations
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+pub fn request_shutdown() {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Time between checking for messages
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time between synchronizations when not given
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(300);
 
 fn sleep() {
-    thread::sleep(::std::time::Duration::from_millis(100))
+    thread::sleep(POLL_INTERVAL)
 }
 
 // TODO: Should probably make my own error type
@@ -27,32 +50,20 @@ fn make_error(error: Box<dyn Error>, description: &str) -> Box<io::Error> {
 
 pub struct Server {
     listener: UnixListener,
+    /// Time between synchronizations
+    interval: Duration,
 }
 
 impl Server {
-    pub fn new() -> io::Result<Server> {
-        let listener = match UnixListener::bind(SOCKET_PATH) {
-            Ok(listener) => listener,
-            Err(_) => {
-                debug!("Could not create listener removing and trying again");
-                fs::remove_file(SOCKET_PATH)?;
-                UnixListener::bind(SOCKET_PATH)?
-            }
-        };
-
-        fs::set_permissions(SOCKET_PATH, PermissionsExt::from_mode(0o766))?;
-
-        listener
-            .set_nonblocking(true)
-            .expect("Could not set listener to non_blocking");
-
-        debug!("Created server listener");
-
-        Ok(Server { listener: listener })
+    pub fn new(interval: Duration) -> io::Result<Server> {
+        Ok(Server {
+            listener: bind()?,
+            interval,
+        })
     }
 
     /// Little wrapper around importer listen so you can still send messages without a valid importer
-    pub fn listen(&self) -> Result<(), Box<dyn Error>> {
+    pub fn listen(&mut self) -> Result<(), Box<dyn Error>> {
         match Config::from_settings() {
             Ok(config) => {
                 let mut importer = match Importer::from_config(config) {
@@ -65,15 +76,24 @@ impl Server {
                     return Err(make_error(e, "Setup failed"));
                 }
 
-                if let Err(e) = importer.listen() {
+                if let Err(e) = importer.listen(self) {
                     return Err(make_error(e, "Could not sync"));
                 }
             }
             Err(e) => {
                 error!("Could not create config: {}", e);
                 loop {
+                    if shutdown_requested() {
+                        break;
+                    }
+                    if take_reload() {
+                        return self.listen();
+                    }
                     if let Ok((stream, _)) = self.listener.accept() {
                         check_messages(stream, |request| get_response_importless(request));
+                        if shutdown_requested() {
+                            break;
+                        }
                         // try again
                         return self.listen();
                     }
@@ -84,14 +104,30 @@ impl Server {
         Ok(())
     }
 
-    /// Will listen for messages for 300+ seconds and will then return
-    pub fn check_messages_for_300(&self, importer: &mut Importer) -> io::Result<()> {
+    /// Time between synchronizations
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Will listen for messages for duration and will then return
+    pub fn check_messages_for(
+        &mut self,
+        importer: &mut Importer,
+        duration: Duration,
+    ) -> io::Result<()> {
+        let iterations = duration.as_millis() / POLL_INTERVAL.as_millis();
         let mut iter = 0;
         loop {
-            if iter > 3000 {
+            if iter > iterations || shutdown_requested() || reload_requested() {
                 return Ok(());
             }
 
+            // removed by the user or by doctor --fix
+            if fs::symlink_metadata(socket_path()).is_err() {
+                info!("Socket was removed, creating it again");
+                self.listener = bind()?;
+            }
+
             if let Ok((stream, _)) = self.listener.accept() {
                 check_messages(stream, |request| get_response(request, importer));
             }
@@ -102,6 +138,41 @@ impl Server {
     }
 }
 
+/// Remove the socket on every way out so clients do not connect to a stopped daemon
+impl Drop for Server {
+    fn drop(&mut self) {
+        info!("Removing socket {:?}", socket_path());
+        if let Err(e) = fs::remove_file(socket_path()) {
+            error!("Could not remove socket: {}", e);
+        }
+    }
+}
+
+/// Create the socket, replacing one left by a daemon that did not stop cleanly
+fn bind() -> io::Result<UnixListener> {
+    if let Some(dir_path) = socket_path().parent() {
+        fs::create_dir_all(dir_path)?;
+    }
+    let listener = match UnixListener::bind(socket_path()) {
+        Ok(listener) => listener,
+        Err(_) => {
+            debug!("Could not create listener removing and trying again");
+            fs::remove_file(socket_path())?;
+            UnixListener::bind(socket_path())?
+        }
+    };
+
+    fs::set_permissions(socket_path(), PermissionsExt::from_mode(0o766))?;
+
+    listener
+        .set_nonblocking(true)
+        .expect("Could not set listener to non_blocking");
+
+    debug!("Created server listener");
+
+    Ok(listener)
+}
+
 fn check_messages<F>(mut stream: UnixStream, op: F)
 where
     F: FnOnce(&str) -> Result<String, String>,
@@ -145,11 +216,11 @@ fn get_response_importless(request_raw: &str) -> Result<String, String> {
             match command {
                 "init" => {
                     if let Some(home_path) = request.next() {
-                        if let Err(e) = Config::write("home_path", home_path) {
+                        if let Err(e) = Config::set_key("home_path", home_path) {
                             return Err(format!("Could not write home path: {}", e));
                         };
                         if let Some(repository) = request.next() {
-                            if let Err(e) = Config::write("repository", repository) {
+                            if let Err(e) = Config::set_key("repository", repository) {
                                 return Err(format!("Could not write repository: {}", e));
                             }
                         }
@@ -161,39 +232,102 @@ fn get_response_importless(request_raw: &str) -> Result<String, String> {
                             }
                         }
                         if let Err(e) =
-                            Config::write("private_key_path", private_key_path.to_str().unwrap())
+                            Config::set_key("private_key_path", private_key_path.to_str().unwrap())
                         {
                             return Err(format!("Could not write private key path: {}", e));
                         }
                     }
                 }
-                "config" => match Config::show_raw() {
-                    Ok(config) => return Ok(config),
-                    Err(e) => return Err(format!("Could not fetch config: {}", e)),
+                "config" => match request.next() {
+                    Some("get") => {
+                        if let Some(key) = request.next() {
+                            return handlers::config_get(key);
+                        }
+                        return Err("Missing key".into());
+                    }
+                    Some("list") => return handlers::config_list(),
+                    Some("set") => {
+                        if let Some(key) = request.next() {
+                            let value = request.collect::<Vec<&str>>().join(" ");
+                            if let Err(e) = Config::set_key(key, &value) {
+                                return Err(format!("Could not set {}: {}", key, e));
+                            }
+                        }
+                    }
+                    Some("unset") => {
+                        if let Some(key) = request.next() {
+                            if let Err(e) = Config::unset_key(key) {
+                                return Err(format!("Could not unset {}: {}", key, e));
+                            }
+                        }
+                    }
+                    _ => match Config::show_raw() {
+                        Ok(config) => return Ok(config),
+                        Err(e) => return Err(format!("Could not fetch config: {}", e)),
+                    },
                 },
                 "set" => {
                     if let Some(arg) = request.next() {
                         if arg.eq("repo") {
                             if let Some(repo) = request.next() {
-                                if let Err(e) = Config::write("repository", repo) {
-                                    return Err(format!("Could not write home path: {}", e));
+                                let mut push = false;
+                                let mut bundle = None;
+                                let mut force = false;
+                                while let Some(arg) = request.next() {
+                                    match arg {
+                                        "--push" => push = true,
+                                        "--bundle" => bundle = request.next(),
+                                        "--force" => force = true,
+                                        _ => return Err(format!("Unknown option {}", arg)),
+                                    }
                                 }
+                                return handlers::set_repository_importless(
+                                    repo, push, bundle, force,
+                                );
                             }
                         } else if arg.eq("home") {
                             if let Some(path) = request.next() {
-                                if let Err(e) = Config::write("home_path", path) {
+                                if let Err(e) = Config::set_key("home_path", path) {
                                     return Err(format!("Could not write home path: {}", e));
                                 }
                             }
                         } else if arg.eq("private_key") {
                             if let Some(path) = request.next() {
-                                if let Err(e) = Config::write("private_key_path", path) {
-                                    return Err(format!("Could not write home path: {}", e));
+                                if let Err(e) = Config::set_key("private_key_path", path) {
+                                    return Err(format!("Could not write private key path: {}", e));
+                                }
+                            }
+                        } else if arg.eq("encryption_key") {
+                            if let Some(path) = request.next() {
+                                let key_path = Path::new(path);
+                                if !key_path.exists() {
+                                    if let Err(e) = generate_key(key_path) {
+                                        return Err(format!("Could not generate key: {}", e));
+                                    }
+                                }
+                                if let Err(e) = Config::set_key("encryption_key_path", path) {
+                                    return Err(format!("Could not write encryption key: {}", e));
+                                }
+                            }
+                        } else if arg.eq("profiles") {
+                            if let Some(profiles) = request.next() {
+                                if let Err(e) = Config::set_key("profiles", profiles) {
+                                    return Err(format!("Could not write profiles: {}", e));
+                                }
+                            }
+                        } else if arg.eq("links") {
+                            if let Some(style) = request.next() {
+                                if let Err(e) = Config::set_key("link_style", style) {
+                                    return Err(format!("Could not write link style: {}", e));
                                 }
                             }
                         }
                     }
                 }
+                "shutdown" => {
+                    request_shutdown();
+                    return Ok("Shutting down".into());
+                }
                 _ => {
                     let config_err = match Config::from_settings() {
                         Ok(_) => return Ok("Valid config. Setting up..".to_string()),
@@ -201,7 +335,7 @@ fn get_response_importless(request_raw: &str) -> Result<String, String> {
                     };
 
                     return Err(
-                        format!("Dimport is unitialized\nInvalid Config: {}\n\nSee the daemon logs and set the correct values using the commands. \nYou can also manually edit the config at `{}` although this is not recommended.", config_err, CONFIG_PATH)
+                        format!("Dimport is unitialized\nInvalid Config: {}\n\nSee the daemon logs and set the correct values using the commands. \nYou can also manually edit the config at `{}` although this is not recommended.", config_err, config_path().display())
                     );
                 }
             };
@@ -226,9 +360,30 @@ fn get_response(request: &str, importer: &mut Importer) -> Result<String, String
             "status" => {
                 return handlers::status(importer);
             }
-            "config" => {
-                return handlers::config(importer);
+            "shutdown" => {
+                request_shutdown();
+                return Ok("Shutting down".into());
             }
+            "config" => match request.next() {
+                Some("get") => {
+                    if let Some(key) = request.next() {
+                        return handlers::config_get(key);
+                    }
+                }
+                Some("list") => return handlers::config_list(),
+                Some("set") => {
+                    if let Some(key) = request.next() {
+                        let value = request.collect::<Vec<&str>>().join(" ");
+                        return handlers::config_set(key, Some(&value), importer);
+                    }
+                }
+                Some("unset") => {
+                    if let Some(key) = request.next() {
+                        return handlers::config_set(key, None, importer);
+                    }
+                }
+                _ => return handlers::config(importer),
+            },
             "sync" => {
                 return handlers::sync(importer);
             }
@@ -236,7 +391,18 @@ fn get_response(request: &str, importer: &mut Importer) -> Result<String, String
                 if let Some(arg) = request.next() {
                     if arg.eq("repo") {
                         if let Some(repo) = request.next() {
-                            return handlers::set_repository(repo, importer);
+                            let mut push = false;
+                            let mut bundle = None;
+                            let mut force = false;
+                            while let Some(arg) = request.next() {
+                                match arg {
+                                    "--push" => push = true,
+                                    "--bundle" => bundle = request.next(),
+                                    "--force" => force = true,
+                                    _ => return Err(format!("Unknown option {}", arg)),
+                                }
+                            }
+                            return handlers::set_repository(repo, push, bundle, force, importer);
                         }
                     } else if arg.eq("home") {
                         if let Some(home) = request.next() {
@@ -246,6 +412,23 @@ fn get_response(request: &str, importer: &mut Importer) -> Result<String, String
                         if let Some(path) = request.next() {
                             return handlers::set_private_key(path, importer);
                         }
+                    } else if arg.eq("encryption_key") {
+                        if let Some(path) = request.next() {
+                            return handlers::set_encryption_key(path, importer);
+                        }
+                    } else if arg.eq("links") {
+                        if let Some(style) = request.next() {
+                            return handlers::set_link_style(style, importer);
+                        }
+                    } else if arg.eq("profiles") {
+                        if let Some(profiles) = request.next() {
+                            return handlers::set_profiles(profiles, importer);
+                        }
+                    } else if arg.eq("variable") {
+                        if let Some(name) = request.next() {
+                            let value = request.collect::<Vec<&str>>().join(" ");
+                            return handlers::set_variable(name, &value, importer);
+                        }
                     }
                 }
             }
@@ -264,8 +447,54 @@ fn get_response(request: &str, importer: &mut Importer) -> Result<String, String
                 }
             }
             "add" => {
+                let mut encrypt = false;
+                let mut confirmed = false;
+                let mut paths = vec![];
+                for arg in request {
+                    match arg {
+                        "--encrypt" => encrypt = true,
+                        "--yes" => confirmed = true,
+                        _ => paths.push(arg),
+                    }
+                }
+                if !paths.is_empty() {
+                    return handlers::add(&paths, encrypt, confirmed, importer);
+                }
+            }
+            "map" => {
+                if let (Some(source), Some(target)) = (request.next(), request.next()) {
+                    return handlers::map(source, target, importer);
+                }
+            }
+            "unmap" => {
+                if let Some(source) = request.next() {
+                    return handlers::unmap(source, importer);
+                }
+            }
+            "forget" => {
+                if let Some(arg) = request.next() {
+                    return handlers::forget(arg, importer);
+                }
+            }
+            "mv" => {
+                if let (Some(old), Some(new)) = (request.next(), request.next()) {
+                    return handlers::move_path(old, new, importer);
+                }
+            }
+            "ls" => {
+                return handlers::ls(request.next() == Some("--tree"), importer);
+            }
+            "which" => {
+                if let Some(arg) = request.next() {
+                    return handlers::which(arg, importer);
+                }
+            }
+            "doctor" => {
+                return handlers::doctor(request.next() == Some("--fix"), importer);
+            }
+            "link_dir" => {
                 if let Some(arg) = request.next() {
-                    return handlers::add(arg, importer);
+                    return handlers::link_dir(arg, importer);
                 }
             }
             "pick" => {
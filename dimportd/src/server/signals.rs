@@ -0,0 +1,35 @@
+use super::request_shutdown;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by SIGHUP, the config is loaded again between operations
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+/// Only sets flags, everything else is not safe to do in a signal handler
+extern "C" fn handle_signal(signal: libc::c_int) {
+    if signal == libc::SIGHUP {
+        RELOAD.store(true, Ordering::SeqCst);
+    } else {
+        request_shutdown();
+    }
+}
+
+/// SIGTERM and SIGINT stop the daemon after the current operation, SIGHUP reloads the config
+pub fn install_signal_handlers() -> io::Result<()> {
+    let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for signal in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+pub fn reload_requested() -> bool {
+    RELOAD.load(Ordering::SeqCst)
+}
+
+/// If a reload was requested since the last call
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
@@ -1,42 +1,65 @@
+use crate::importer::config::{Config, LinkStyle};
 use crate::importer::state::Difference;
 use git2::Cred;
 use git2::RemoteCallbacks;
 use git2::Repository;
 use std::error::Error;
+use std::os::unix::fs::symlink;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{
     fs, io,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
 use log::{debug, info};
 
-pub fn find_equal_files<F>(
-    src: &Path,
-    dest: &Path,
-    cur: &Path,
-    ignore_files: &Vec<PathBuf>,
-    op: &mut F,
-) -> io::Result<()>
+/// Find all files in src and the path they are deployed to according to config
+pub fn find_equal_files<F>(src: &Path, config: &Config, cur: &Path, op: &mut F) -> io::Result<()>
 where
     F: FnMut(&Path, &Path, &Path) -> io::Result<()>,
 {
     let cur_dir = src.join(cur);
 
     if cur_dir.is_dir() {
-        for entry in fs::read_dir(cur_dir)? {
-            let entry = entry?;
-            let path = entry.path();
+        let paths = fs::read_dir(cur_dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<PathBuf>>>()?;
+        // file names in this directory to pick variants from
+        let names: Vec<String> = paths
+            .iter()
+            .filter(|path| path.is_file())
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
 
-            if ignore_files.contains(&path.to_path_buf()) {
+        for path in paths.iter() {
+            if config.ignore_files.contains(&path.to_path_buf())
+                || config.manifest.is_ignored(path.strip_prefix(src).unwrap())
+            {
                 debug!("Ignoring {:?}", path);
                 continue;
             }
+            if !config.is_selected(path.strip_prefix(src).unwrap()) {
+                debug!("Not selected for this host or profile {:?}", path);
+                continue;
+            }
 
             if path.is_dir() {
                 let cur = path.strip_prefix(src).unwrap();
-                find_equal_files(src, dest, &cur, ignore_files, op)?;
+                // linked directories are handled as if they are a single file
+                if config.linked_dirs.iter().any(|dir| dir == cur) {
+                    op(path, &config.target_path(cur), cur.parent().unwrap())?;
+                    continue;
+                }
+                find_equal_files(src, config, &cur, op)?;
             } else if path.is_file() {
-                op(&path, &dest.join(cur).join(path.file_name().unwrap()), cur)?;
+                let name = path.file_name().unwrap().to_string_lossy();
+                if !config.is_chosen_variant(&name, &names) {
+                    debug!("Not the variant for this host or profile {:?}", path);
+                    continue;
+                }
+                let target = config.target_path(&cur.join(path.file_name().unwrap()));
+                op(path, &target, cur)?;
             }
         }
     }
@@ -44,9 +67,10 @@ where
 }
 
 /// Find all directories that are equal to src. Returns dest dirs.
+/// Does not go into linked directories
 pub fn find_equal_dir<F>(
     src_path: &Path,
-    dest_path: &Path,
+    config: &Config,
     relative_path: &Path,
     op: &mut F,
 ) -> io::Result<()>
@@ -56,37 +80,45 @@ where
     let src_cur = src_path.join(relative_path);
 
     if src_cur.is_dir() {
-        let dest_cur = dest_path.join(relative_path);
-        // skip if does not exist
-        if !dest_cur.exists() {
-            return Ok(());
+        let dest_cur = config.target_path(relative_path);
+        // a mapped sub directory can exist without its parent
+        if dest_cur.exists() {
+            op(&dest_cur)?;
         }
-        op(&dest_cur)?;
         for entry in fs::read_dir(src_cur)? {
             let entry = entry?;
             let src_entry = entry.path();
 
             if src_entry.is_dir() {
                 let current_relative_path = src_entry.strip_prefix(src_path).unwrap();
-                find_equal_dir(src_path, dest_path, current_relative_path, op)?;
+                if config
+                    .linked_dirs
+                    .iter()
+                    .any(|dir| dir == current_relative_path)
+                    || config.manifest.is_ignored(current_relative_path)
+                    || !config.is_selected(current_relative_path)
+                {
+                    continue;
+                }
+                find_equal_dir(src_path, config, current_relative_path, op)?;
             }
         }
     }
     Ok(())
 }
 
-/// Find all files if directly in one of the same folders of src_path without following symlinks
-pub fn _find_all_files_symlink<F>(path: &Path, op: &mut F) -> io::Result<()>
+/// Find all files and symlinks in path without following symlinks
+pub fn find_all_files_symlink<F>(path: &Path, op: &mut F) -> io::Result<()>
 where
     F: FnMut(&Path) -> io::Result<()>,
 {
-    if path.is_dir() {
+    if path.symlink_metadata()?.is_dir() {
         for entry in fs::read_dir(path)? {
             let path = entry?.path();
 
-            if path.is_dir() {
-                _find_all_files_symlink(&path, op)?;
-            } else if path.symlink_metadata()?.is_file() {
+            if path.symlink_metadata()?.is_dir() {
+                find_all_files_symlink(&path, op)?;
+            } else {
                 op(&path)?;
             }
         }
@@ -94,6 +126,246 @@ where
     Ok(())
 }
 
+/// Recursively copy a directory, symlinks are copied as symlinks
+pub fn copy_dir(src: &Path, dest: &Path) -> io::Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(src)? {
+        let path = entry?.path();
+        let dest_path = dest.join(path.file_name().unwrap());
+        let file_type = path.symlink_metadata()?.file_type();
+
+        if file_type.is_dir() {
+            copy_dir(&path, &dest_path)?;
+        } else if file_type.is_symlink() {
+            symlink(fs::read_link(&path)?, &dest_path)?;
+        } else {
+            fs::copy(&path, &dest_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Symlink dest to src, relative to the parent of dest when using LinkStyle::Relative
+pub fn symlink_with_style(src: &Path, dest: &Path, style: LinkStyle) -> io::Result<()> {
+    match style {
+        LinkStyle::Absolute => symlink(src, dest),
+        LinkStyle::Relative => {
+            // the kernel resolves the link from the real directories
+            let from = fs::canonicalize(dest.parent().unwrap())?;
+            let to = match src.parent() {
+                Some(parent) => fs::canonicalize(parent)?.join(src.file_name().unwrap()),
+                None => src.to_path_buf(),
+            };
+            symlink(relative_path(&from, &to), dest)
+        }
+    }
+}
+
+/// Expand a leading `~` to home and `$VAR` or `${VAR}` to environment variables
+/// XDG base directories fall back to their defaults inside of home
+pub fn expand_path(path: &str, home: &Path) -> PathBuf {
+    let path = match path.strip_prefix('~') {
+        Some(rest) => format!("{}{}", home.to_str().unwrap(), rest),
+        None => path.to_string(),
+    };
+
+    let mut expanded = String::new();
+    let mut chars = path.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+        let braced = chars.peek() == Some(&'{');
+        if braced {
+            chars.next();
+        }
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                name.push(c);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        if braced && chars.peek() == Some(&'}') {
+            chars.next();
+        }
+        expanded.push_str(&env_var_or_default(&name, home));
+    }
+
+    PathBuf::from(expanded)
+}
+
+/// HOME is always the configured home
+fn env_var_or_default(name: &str, home: &Path) -> String {
+    if name == "HOME" {
+        return home.to_str().unwrap().to_string();
+    }
+    if let Ok(value) = std::env::var(name) {
+        return value;
+    }
+    let default = match name {
+        "XDG_CONFIG_HOME" => home.join(".config"),
+        "XDG_DATA_HOME" => home.join(".local/share"),
+        "XDG_STATE_HOME" => home.join(".local/state"),
+        "XDG_CACHE_HOME" => home.join(".cache"),
+        _ => PathBuf::new(),
+    };
+    default.to_str().unwrap().to_string()
+}
+
+/// Split a file name like `.gitconfig##host.laptop` into its base name and variant conditions
+pub fn split_variant(name: &str) -> (&str, Option<&str>) {
+    match name.split_once("##") {
+        Some((base, conditions)) => (base, Some(conditions)),
+        None => (name, None),
+    }
+}
+
+/// Path with the variant conditions removed from its file name
+pub fn strip_variant(path: &Path) -> PathBuf {
+    if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+        if let (base, Some(_)) = split_variant(name) {
+            return path.with_file_name(base);
+        }
+    }
+    path.to_path_buf()
+}
+
+/// FNV-1a hash as hex, stable between builds so it can be saved
+pub fn hash(data: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// Seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// Time since a unix time like "5 minutes ago"
+pub fn format_elapsed(time: u64) -> String {
+    let seconds = now().saturating_sub(time);
+    let (amount, unit) = match seconds {
+        0..=59 => (seconds, "second"),
+        60..=3599 => (seconds / 60, "minute"),
+        3600..=86399 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day"),
+    };
+    let plural = if amount == 1 { "" } else { "s" };
+    format!("{} {}{} ago", amount, unit, plural)
+}
+
+/// Name of this machine
+pub fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Path to `to` as seen from directory `from`, both have to be absolute
+pub fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<Component> = from.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    let common = from
+        .iter()
+        .zip(to.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    for component in &to[common..] {
+        path.push(component);
+    }
+    path
+}
+
+/// Absolute path a symlink points to, relative links are resolved from the real parent of link
+pub fn resolve_symlink(link: &Path) -> io::Result<PathBuf> {
+    let target = fs::read_link(link)?;
+    if target.is_absolute() {
+        return Ok(target);
+    }
+    let parent = link.parent().unwrap();
+    let mut path = fs::canonicalize(parent).unwrap_or_else(|_| parent.to_path_buf());
+    for component in target.components() {
+        match component {
+            Component::ParentDir => {
+                path.pop();
+            }
+            Component::CurDir => {}
+            component => path.push(component),
+        }
+    }
+    Ok(path)
+}
+
+/// If link points to path, also when directories on the way are symlinks
+pub fn links_to(link: &Path, path: &Path) -> bool {
+    let target = match resolve_symlink(link) {
+        Ok(target) => target,
+        Err(_) => return false,
+    };
+    if target == path {
+        return true;
+    }
+    match (fs::canonicalize(&target), fs::canonicalize(path)) {
+        (Ok(target), Ok(path)) => target == path,
+        _ => false,
+    }
+}
+
+/// Commits of master that are not on origin/master and the other way around
+pub fn repository_ahead_behind(repository: &Repository) -> Result<(usize, usize), git2::Error> {
+    let local = repository.refname_to_id("refs/heads/master")?;
+    let upstream = repository.refname_to_id("refs/remotes/origin/master")?;
+    repository.graph_ahead_behind(local, upstream)
+}
+
+/// Changed files and commits that are not on origin/master, lost when the clone is removed
+pub fn repository_local_work(repository: &Repository) -> Result<(usize, usize), git2::Error> {
+    let mut options = git2::StatusOptions::new();
+    options.include_untracked(true).include_ignored(false);
+    let changed = repository.statuses(Some(&mut options))?.len();
+    let unpushed = match repository_ahead_behind(repository) {
+        Ok((ahead, _)) => ahead,
+        // nothing to compare with in an empty repository
+        Err(e) if e.code() == git2::ErrorCode::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    Ok((changed, unpushed))
+}
+
+/// Export every commit to a bundle file that can be cloned or fetched from
+pub fn repository_bundle(repository: &Repository, path: &Path) -> io::Result<()> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repository.workdir().unwrap())
+        .args(["bundle", "create"])
+        .arg(path)
+        .arg("--all")
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(())
+}
+
 pub fn repository_push(
     repository: &git2::Repository,
     private_key_path: &Path,
@@ -112,7 +384,11 @@ pub fn repository_commit(
     let signature = get_signature()?;
     let mut index = repository.index()?;
     for path in paths.iter() {
-        index.add_path(path)?;
+        if repository.workdir().unwrap().join(path).exists() {
+            index.add_path(path)?;
+        } else {
+            index.remove_path(path)?;
+        }
     }
     index.write()?;
     let oid = index.write_tree()?;
@@ -163,6 +439,20 @@ pub fn repository_update(
     remote.fetch(&["master"], Some(&mut options), None)?;
     Ok(())
 }
+/// Connect to origin without fetching to see if it is reachable
+pub fn repository_check_remote(
+    repository: &Repository,
+    private_key_path: &Path,
+) -> Result<(), git2::Error> {
+    let mut remote = repository.find_remote("origin")?;
+    remote.connect_auth(
+        git2::Direction::Fetch,
+        Some(get_callbacks(private_key_path)),
+        None,
+    )?;
+    remote.disconnect()?;
+    Ok(())
+}
 pub fn repository_fetch(
     url: &str,
     path: &Path,
@@ -195,9 +485,20 @@ pub fn repository_fetch(
     if let Err(_) = repo.find_remote("origin") {
         repo.remote("origin", url)?;
     }
-    // if current repo differs remove and fetch again
-    if repo.find_remote("origin")?.url().unwrap() != url {
-        fs::remove_dir_all(&path)?;
+    // if current repo differs remove and fetch again, unless that loses work
+    let current_url = repo.find_remote("origin")?.url().unwrap().to_string();
+    if current_url != url {
+        let (changed, unpushed) = repository_local_work(&repo)?;
+        if changed > 0 || unpushed > 0 {
+            return Err(io::Error::other(format!(
+                "{:?} is a clone of {} with {} changed files and {} unpushed commits, \
+                use `dimport set repo <url>` with --push, --bundle <path> or --force to switch",
+                path, current_url, changed, unpushed
+            ))
+            .into());
+        }
+        drop(repo);
+        fs::remove_dir_all(path)?;
         return repository_fetch(url, path, private_key_path);
     }
 
@@ -226,7 +527,7 @@ fn get_callbacks<'a>(private_key_path: &'a Path) -> RemoteCallbacks<'a> {
     callbacks
 }
 
-fn get_signature<'a>() -> Result<git2::Signature<'a>, git2::Error> {
+pub fn get_signature<'a>() -> Result<git2::Signature<'a>, git2::Error> {
     let config = git2::Config::open_default()?;
 
     let name = config.get_entry("user.name")?;
@@ -238,3 +539,90 @@ fn get_signature<'a>() -> Result<git2::Signature<'a>, git2::Error> {
 
     git2::Signature::now(&name, &email)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory for a test, removed when it starts
+    fn test_dir(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("dimport-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn relative_path_climbs_out_of_directories() {
+        let path = relative_path(
+            Path::new("/home/user/.config/i3"),
+            Path::new("/home/user/repo/i3"),
+        );
+        assert_eq!(path, Path::new("../../repo/i3"));
+        let path = relative_path(
+            Path::new("/home/user"),
+            Path::new("/home/user/repo/.bashrc"),
+        );
+        assert_eq!(path, Path::new("repo/.bashrc"));
+    }
+
+    #[test]
+    fn relative_links_resolve_through_symlinked_directories() {
+        let path = test_dir("links");
+        fs::create_dir_all(path.join("repo/config")).unwrap();
+        fs::create_dir_all(path.join("dotfiles/config")).unwrap();
+        fs::write(path.join("repo/config/app"), "").unwrap();
+        symlink(path.join("dotfiles/config"), path.join("config")).unwrap();
+
+        let source = path.join("repo/config/app");
+        let link = path.join("config/app");
+        symlink_with_style(&source, &link, LinkStyle::Relative).unwrap();
+        assert_eq!(
+            fs::read_link(&link).unwrap(),
+            Path::new("../../repo/config/app")
+        );
+        assert!(links_to(&link, &source));
+        assert_eq!(
+            fs::canonicalize(resolve_symlink(&link).unwrap()).unwrap(),
+            fs::canonicalize(&source).unwrap()
+        );
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn expand_path_replaces_home_and_variables() {
+        let home = Path::new("/home/user");
+        assert_eq!(
+            expand_path("~/.bashrc", home),
+            Path::new("/home/user/.bashrc")
+        );
+        assert_eq!(
+            expand_path("$HOME/.vimrc", home),
+            Path::new("/home/user/.vimrc")
+        );
+        assert_eq!(expand_path("${HOME}/x", home), Path::new("/home/user/x"));
+        assert_eq!(expand_path("$DIMPORT_TEST_UNSET/x", home), Path::new("/x"));
+        assert_eq!(expand_path("/etc/hosts", home), Path::new("/etc/hosts"));
+    }
+
+    #[test]
+    fn fetch_refuses_to_replace_local_work() {
+        let path = test_dir("fetch");
+        let repository = Repository::init(&path).unwrap();
+        repository
+            .remote("origin", "file:///nonexistent/old.git")
+            .unwrap();
+        fs::write(path.join(".bashrc"), "unsaved").unwrap();
+
+        let result = repository_fetch("file:///nonexistent/new.git", &path, Path::new("key"));
+        let error = result.err().unwrap().to_string();
+        assert!(
+            error.contains("1 changed files and 0 unpushed commits"),
+            "{}",
+            error
+        );
+        assert!(path.join(".bashrc").exists());
+        fs::remove_dir_all(&path).unwrap();
+    }
+}